@@ -0,0 +1,113 @@
+use crate::variables::Variables;
+
+// Evaluates an integer expression made of numbers, variable names, the
+// operators `+ - * / %` and parentheses. Variables that are unset or do not
+// hold a number count as 0.
+pub fn evaluate(expression: &str, variables: &Variables) -> Result<i64, String> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut position = 0;
+
+    let value = sum(&chars, &mut position, variables)?;
+    skip_blanks(&chars, &mut position);
+
+    if position < chars.len() {
+        return Err(format!("{}: syntax error in expression", expression.trim()));
+    }
+    Ok(value)
+}
+
+fn skip_blanks(chars: &[char], position: &mut usize) {
+    while *position < chars.len() && chars[*position].is_whitespace() {
+        *position += 1;
+    }
+}
+
+fn sum(chars: &[char], position: &mut usize, variables: &Variables) -> Result<i64, String> {
+    let mut value = product(chars, position, variables)?;
+
+    loop {
+        skip_blanks(chars, position);
+        match chars.get(*position) {
+            Some('+') => {
+                *position += 1;
+                value = value.wrapping_add(product(chars, position, variables)?);
+            }
+            Some('-') => {
+                *position += 1;
+                value = value.wrapping_sub(product(chars, position, variables)?);
+            }
+            _ => return Ok(value),
+        }
+    }
+}
+
+fn product(chars: &[char], position: &mut usize, variables: &Variables) -> Result<i64, String> {
+    let mut value = unary(chars, position, variables)?;
+
+    loop {
+        skip_blanks(chars, position);
+        let operator = match chars.get(*position) {
+            Some(&c) if matches!(c, '*' | '/' | '%') => c,
+            _ => return Ok(value),
+        };
+        *position += 1;
+
+        let right = unary(chars, position, variables)?;
+        value = match operator {
+            '*' => value.wrapping_mul(right),
+            _ if right == 0 => return Err("division by 0".to_string()),
+            '/' => value.wrapping_div(right),
+            _ => value.wrapping_rem(right),
+        };
+    }
+}
+
+fn unary(chars: &[char], position: &mut usize, variables: &Variables) -> Result<i64, String> {
+    skip_blanks(chars, position);
+
+    match chars.get(*position) {
+        Some('-') => {
+            *position += 1;
+            Ok(unary(chars, position, variables)?.wrapping_neg())
+        }
+        Some('+') => {
+            *position += 1;
+            unary(chars, position, variables)
+        }
+        Some('(') => {
+            *position += 1;
+            let value = sum(chars, position, variables)?;
+            skip_blanks(chars, position);
+            if chars.get(*position) != Some(&')') {
+                return Err("missing `)'".to_string());
+            }
+            *position += 1;
+            Ok(value)
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let start = *position;
+            while chars.get(*position).is_some_and(|c| c.is_ascii_digit()) {
+                *position += 1;
+            }
+
+            let digits: String = chars[start..*position].iter().collect();
+            digits
+                .parse()
+                .map_err(|_| format!("{}: value too great for base", digits))
+        }
+        Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+            let start = *position;
+            while chars
+                .get(*position)
+                .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_')
+            {
+                *position += 1;
+            }
+
+            let name: String = chars[start..*position].iter().collect();
+            let value = variables.get(&name).unwrap_or_default();
+            Ok(value.trim().parse().unwrap_or(0))
+        }
+        _ => Err("syntax error: operand expected".to_string()),
+    }
+}
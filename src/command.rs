@@ -1,22 +1,43 @@
 use std::ffi::CString;
+use std::fmt;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::os::fd::FromRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 use libc::{
-    access, close, dup, dup2, execve, exit, fork, getpgrp, getpid, ioctl, open, pipe, setpgid,
-    signal, tcsetpgrp, waitpid,
+    access, close, dup2, execve, exit, fcntl, fork, getpgrp, getpid, ioctl, isatty, kill, lseek,
+    mkstemp, open, pipe2, poll, raise, setpgid, sigaction, sigemptyset, tcgetattr, tcsetattr,
+    tcsetpgrp, unlink, write,
 };
-use libc::{c_char, c_int};
+use libc::{c_char, c_int, pid_t, pollfd, termios};
 use libc::{
-    O_APPEND, O_CREAT, O_RDONLY, O_TRUNC, O_WRONLY, SIGINT, SIGQUIT, SIG_DFL, TIOCSPGRP, X_OK,
+    EBADF, ECHO, EINTR, ENOENT, F_DUPFD_CLOEXEC, ICANON, O_APPEND, O_CLOEXEC, O_CREAT, O_RDONLY,
+    O_TRUNC, O_WRONLY, POLLIN, SEEK_SET, SIGINT, SIGTERM, TCSANOW, TIOCSPGRP, VMIN, VTIME, X_OK,
 };
-use libc::{WEXITSTATUS, WIFEXITED};
+
+use crate::arithmetic;
+use crate::expand::{
+    array_literal, decode_escapes, expand_array, expand_assignment, expand_heredoc, expand_pattern,
+    expand_string, expand_word, ifs, is_assignment, is_name, split_assignment, split_fields,
+    subscript,
+};
+use crate::glob;
+use crate::input::{input_history, input_history_entries};
+use crate::jobs::{exit_status, waitpid};
+use crate::options::Options;
+use crate::shell::Shell;
+use crate::signals;
+use crate::times::{Times, DEFAULT_FORMAT};
+use crate::variables::Variable;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Operator {
-    Semicolon,  // `;`
-    Background, // `&`
-    And,        // `&&`
-    Or,         // `||`
-    Pipe,       // `|`
+    Semicolon, // `;` and `&`, which wraps its left side in `Background`
+    And,       // `&&`
+    Or,        // `||`
+    Pipe,      // `|`
 }
 
 #[derive(Debug, Clone)]
@@ -30,6 +51,29 @@ pub struct Redirection {
 pub enum RedirectTarget {
     File(String),        // e.g., `> file.txt`
     FileDescriptor(u32), // e.g., `2>&1`
+    Close,               // e.g., `3<&-`
+    HereDoc(HereDoc),    // e.g., `<<EOF`
+}
+
+// A `pattern | pattern) body` clause of `case`.
+#[derive(Debug, Clone)]
+pub struct CaseClause {
+    pub patterns: Vec<String>,
+    pub body: Option<Command>, // `None` for a clause without commands
+    pub terminator: CaseTerminator,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CaseTerminator {
+    Break,       // `;;`, or none before `esac`
+    Fallthrough, // `;&` runs the next body without testing its patterns
+    Continue,    // `;;&` goes on testing the patterns of the next clauses
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HereDoc {
+    pub body: String,
+    pub quoted: bool, // A quoted delimiter disables expansion of the body
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -42,10 +86,49 @@ pub enum RedirectOperator {
     DuplicateOut, // `>&`
 }
 
+impl fmt::Display for RedirectOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operator = match self {
+            RedirectOperator::Overwrite => ">",
+            RedirectOperator::Append => ">>",
+            RedirectOperator::Input => "<",
+            RedirectOperator::HereDoc => "<<",
+            RedirectOperator::DuplicateIn => "<&",
+            RedirectOperator::DuplicateOut => ">&",
+        };
+
+        write!(f, "{}", operator)
+    }
+}
+
+// Opens the files named by `<`, `>` and `>>` redirections. The shell goes
+// through it instead of calling `open` itself so that tests can hand out
+// descriptors of their own and check which files were opened, with which
+// flags. Here-documents do not go through it: their bodies are written to a
+// temporary file of `heredoc_fd`'s own.
+pub trait FdOpener {
+    fn open(&self, path: &str, flags: c_int) -> std::io::Result<c_int>;
+}
+
+// Opens files for real, creating them with mode 0644 before the umask.
+pub struct SystemOpener;
+
+impl FdOpener for SystemOpener {
+    fn open(&self, path: &str, flags: c_int) -> std::io::Result<c_int> {
+        let c_path = CString::new(path).map_err(|_| std::io::Error::from_raw_os_error(ENOENT))?;
+        let fd = unsafe { open(c_path.as_ptr(), flags, 0o644) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(fd)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Command {
     Simple {
-        executable: String,
+        assignments: Vec<String>, // `NAME=value` words preceding the command
+        executable: String,       // Empty when the command is only assignments
         args: Vec<String>,
         redirects: Vec<Redirection>,
     },
@@ -57,13 +140,111 @@ pub enum Command {
     },
 
     Group {
-        group: Box<Command>,
+        group: Box<Command>, // `( ... )`, run in a subshell
+    },
+
+    BraceGroup {
+        group: Box<Command>, // `{ ...; }`, run in the current shell
+    },
+
+    Background {
+        command: Box<Command>,
+    },
+
+    Function {
+        name: String,
+        body: Box<Command>, // A brace group or subshell run on each call
+    },
+
+    Timed {
+        command: Box<Command>, // `time pipeline`
+    },
+
+    Not {
+        command: Box<Command>, // `! pipeline`
+    },
+
+    Coproc {
+        name: String, // The array holding the descriptors, `COPROC` by default
+        command: Box<Command>,
+    },
+
+    Case {
+        word: String,
+        clauses: Vec<CaseClause>,
+    },
+
+    Select {
+        name: String,
+        words: Option<Vec<String>>, // The positional parameters when `None`
+        body: Box<Command>,
+    },
+
+    If {
+        branches: Vec<(Command, Command)>, // Conditions of `if` and `elif` with their bodies
+        otherwise: Option<Box<Command>>,   // `else`
+    },
+
+    While {
+        condition: Box<Command>,
+        body: Box<Command>,
+        until: bool, // Loops while the condition fails
+    },
+
+    For {
+        name: String,
+        words: Option<Vec<String>>, // The positional parameters when `None`
+        body: Box<Command>,
+    },
+
+    Redirected {
+        command: Box<Command>, // A compound command, `{ ...; } > file`
+        redirects: Vec<Redirection>,
     },
 }
 
 impl Command {
-    fn redirect(&self) -> Result<(), String> {
-        if let Command::Simple { redirects, .. } = self {
+    // Performs the redirections in the shell itself, returning copies of the
+    // descriptors they replace for `restore_fds`. Errors are reported here.
+    fn redirect_saving(
+        &self,
+        redirects: &[Redirection],
+        shell: &mut Shell,
+    ) -> Option<std::collections::HashMap<u32, c_int>> {
+        let mut saved_fds = std::collections::HashMap::new();
+
+        for redirection in redirects {
+            let fd = redirection.fd.unwrap_or(match redirection.operator {
+                RedirectOperator::Input
+                | RedirectOperator::HereDoc
+                | RedirectOperator::DuplicateIn => 0,
+                _ => 1,
+            });
+
+            // Copies are kept above the descriptors users can name, and a
+            // descriptor that wasn't open is closed again afterwards.
+            if !saved_fds.contains_key(&fd) {
+                let saved_fd = unsafe { fcntl(fd as c_int, F_DUPFD_CLOEXEC, 10) };
+                let error = std::io::Error::last_os_error();
+                if saved_fd == -1 && error.raw_os_error() != Some(EBADF) {
+                    eprintln!("rush: {}: {}", fd, error_message(&error));
+                    restore_fds(saved_fds);
+                    return None;
+                }
+                saved_fds.insert(fd, saved_fd);
+            }
+        }
+
+        if let Err(e) = self.redirect(shell) {
+            eprintln!("rush: {}", e);
+            restore_fds(saved_fds);
+            return None;
+        }
+        Some(saved_fds)
+    }
+
+    fn redirect(&self, shell: &mut Shell) -> Result<(), String> {
+        if let Command::Simple { redirects, .. } | Command::Redirected { redirects, .. } = self {
             for redirection in redirects {
                 let fd = redirection.fd.unwrap_or(match redirection.operator {
                     RedirectOperator::Input
@@ -74,24 +255,68 @@ impl Command {
 
                 match &redirection.target {
                     RedirectTarget::File(path) => {
-                        let c_path = CString::new(path.as_str()).unwrap();
-                        let mode = match redirection.operator {
+                        let mut fields = expand_word(path, shell)?;
+                        if fields.len() != 1 {
+                            return Err(format!("{}: ambiguous redirect", path));
+                        }
+
+                        let file = fields.remove(0);
+
+                        // The descriptor of `>&$fd` is only known once expanded.
+                        if matches!(
+                            redirection.operator,
+                            RedirectOperator::DuplicateIn | RedirectOperator::DuplicateOut
+                        ) {
+                            match file.parse::<c_int>() {
+                                Ok(target_fd) if unsafe { dup2(target_fd, fd as c_int) } < 0 => {
+                                    return Err(format!("{}: Bad file descriptor", target_fd));
+                                }
+                                Ok(_) => {}
+                                Err(_) if file == "-" => unsafe {
+                                    close(fd as c_int);
+                                },
+                                Err(_) => return Err(format!("{}: ambiguous redirect", path)),
+                            }
+                            continue;
+                        }
+
+                        let flags = match redirection.operator {
                             RedirectOperator::Overwrite => O_WRONLY | O_CREAT | O_TRUNC,
                             RedirectOperator::Append => O_WRONLY | O_CREAT | O_APPEND,
                             RedirectOperator::Input => O_RDONLY,
                             _ => return Err("Unsupported redirection type".into()),
                         };
 
-                        let target_fd = unsafe { open(c_path.as_ptr(), mode, 0o644) };
-                        if target_fd < 0 {
-                            return Err("Failed to open file".into());
-                        }
+                        let target_fd = shell
+                            .opener
+                            .open(&file, flags)
+                            .map_err(|error| format!("{}: {}", file, error_message(&error)))?;
 
-                        unsafe { dup2(target_fd, fd as c_int) };
-                        unsafe { close(target_fd) };
+                        if target_fd != fd as c_int {
+                            unsafe { dup2(target_fd, fd as c_int) };
+                            unsafe { close(target_fd) };
+                        }
                     }
                     RedirectTarget::FileDescriptor(target_fd) => {
-                        unsafe { dup2(*target_fd as c_int, fd as c_int) };
+                        if unsafe { dup2(*target_fd as c_int, fd as c_int) } < 0 {
+                            return Err(format!("{}: Bad file descriptor", target_fd));
+                        }
+                    }
+                    RedirectTarget::Close => {
+                        unsafe { close(fd as c_int) };
+                    }
+                    RedirectTarget::HereDoc(heredoc) => {
+                        let body = if heredoc.quoted {
+                            heredoc.body.clone()
+                        } else {
+                            expand_heredoc(&heredoc.body, shell)
+                        };
+
+                        let target_fd = heredoc_fd(&body)?;
+                        if target_fd != fd as c_int {
+                            unsafe { dup2(target_fd, fd as c_int) };
+                            unsafe { close(target_fd) };
+                        }
                     }
                 }
             }
@@ -100,102 +325,202 @@ impl Command {
         Ok(())
     }
 
-    pub fn execute(&self) -> i32 {
+    // `DEBUG` runs before each simple command and `ERR` after a simple
+    // command, pipeline or subshell fails, outside of `&&` and `||`
+    // conditions. With `noexec` commands are only parsed, from the one after
+    // `set -n` on, except in an interactive shell where it could never be
+    // turned off again.
+    pub fn execute(&self, shell: &mut Shell) -> i32 {
+        if shell.options.enabled("noexec") && !shell.job_control {
+            return 0;
+        }
+
+        let simple = matches!(self, Command::Simple { .. });
+        if simple {
+            shell.run_trap("DEBUG");
+        }
+
+        let substitutions = shell.substitutions.len();
+        let status = self.execute_command(shell);
+        close_substitutions(substitutions, shell);
+        shell.last_status = status;
+        signals::run_pending(shell);
+
+        let pipeline = matches!(
+            self,
+            Command::Binary {
+                operator: Operator::Pipe,
+                ..
+            } | Command::Group { .. }
+        );
+        if status != 0 && (simple || pipeline) && !shell.in_condition {
+            shell.run_trap("ERR");
+        }
+        status
+    }
+
+    fn execute_command(&self, shell: &mut Shell) -> i32 {
         match self {
             Command::Simple {
-                args, redirects, ..
+                assignments,
+                executable,
+                args,
+                redirects,
             } => {
-                if self.is_builtin() {
-                    let mut saved_fds = std::collections::HashMap::new();
-
-                    for redirection in redirects {
-                        let fd = redirection
-                            .fd
-                            .unwrap_or_else(|| match redirection.operator {
-                                RedirectOperator::Input
-                                | RedirectOperator::HereDoc
-                                | RedirectOperator::DuplicateIn => 0,
-                                _ => 1,
-                            });
+                // A malformed `${...}` fails the command without running it.
+                shell.bad_substitution = false;
+                let argv = match expand_argv(executable, args, shell) {
+                    Ok(_) if shell.bad_substitution => return 1,
+                    Ok(argv) => argv,
+                    Err(e) => {
+                        eprintln!("rush: {}", e);
+                        return 1;
+                    }
+                };
 
-                        if !saved_fds.contains_key(&fd) {
-                            let saved_fd = unsafe { dup(fd as c_int) };
-                            if saved_fd == -1 {
-                                eprintln!("Failed to save file descriptor {}", fd);
-                                for (_, saved_fd) in saved_fds {
-                                    unsafe { close(saved_fd) };
-                                }
-                                return 1;
-                            }
-                            saved_fds.insert(fd, saved_fd);
+                // Without a command word the assignments apply to the shell
+                // itself rather than to a single command, and redirections
+                // are only performed for their side effects, like creating
+                // or truncating a file. The status is the one of the last
+                // command substitution in them, if any.
+                if argv.is_empty() {
+                    shell.substitution_status = None;
+                    match self.redirect_saving(redirects, shell) {
+                        Some(saved_fds) => restore_fds(saved_fds),
+                        None => return 1,
+                    }
+
+                    for assignment in assignments {
+                        if let Err(e) = assign(assignment, shell) {
+                            eprintln!("rush: {}", e);
+                            return 1;
+                        }
+                        if shell.bad_substitution {
+                            return 1;
                         }
                     }
+                    return shell.substitution_status.take().unwrap_or(0);
+                }
 
-                    if let Err(e) = self.redirect() {
-                        eprintln!("Redirection error: {}", e);
-                        for (fd, saved_fd) in saved_fds {
-                            unsafe {
-                                dup2(saved_fd, fd as c_int);
-                                close(saved_fd);
+                let assignments: Vec<(String, String)> = assignments
+                    .iter()
+                    .map(|assignment| {
+                        let assignment = expand_assignment(assignment, shell);
+                        let (name, append, value) = split_assignment(&assignment).unwrap();
+                        match append {
+                            true => {
+                                let current = shell.variables.get(name).unwrap_or("");
+                                (name.to_string(), format!("{}{}", current, value))
                             }
+                            false => (name.to_string(), value.to_string()),
                         }
-                        return 1;
-                    }
+                    })
+                    .collect();
+                if shell.bad_substitution {
+                    return 1;
+                }
+
+                let readonly = assignments
+                    .iter()
+                    .find(|(name, _)| shell.variables.variable(name).is_some_and(|v| v.readonly));
+                if let Some((name, _)) = readonly {
+                    eprintln!("rush: {}: readonly variable", name);
+                    return 1;
+                }
+
+                if argv[0] == "exec" {
+                    return builtin_exec(self, &argv[1..], &assignments, shell);
+                }
 
-                    let exit_code = self.execute_builtin();
+                let function = shell.functions.get(&argv[0]).cloned();
+                if function.is_some() || is_builtin(&argv[0]) {
+                    // The redirections, stderr included, are in place in the
+                    // shell for as long as the builtin runs, so that its error
+                    // messages follow `2>file` like those of any command.
+                    let saved_fds = match self.redirect_saving(redirects, shell) {
+                        Some(saved_fds) => saved_fds,
+                        None => return 1,
+                    };
 
-                    for (fd, saved_fd) in saved_fds {
-                        unsafe {
-                            dup2(saved_fd, fd as c_int);
-                            close(saved_fd);
-                        }
+                    let saved_vars: Vec<(String, Option<Variable>)> = assignments
+                        .iter()
+                        .map(|(name, value)| {
+                            let saved = shell.variables.variable(name).cloned();
+                            let _ = shell.variables.set(name, value);
+                            shell.variables.export(name);
+                            (name.clone(), saved)
+                        })
+                        .collect();
+
+                    let exit_code = match function {
+                        Some(body) => call_function(&body, &argv, shell),
+                        None => execute_builtin(&argv, shell),
+                    };
+
+                    for (name, saved) in saved_vars.into_iter().rev() {
+                        shell.variables.restore(&name, saved);
                     }
 
+                    restore_fds(saved_fds);
+
                     exit_code
                 } else {
-                    let path = self.path();
+                    let path = path(&argv[0], shell);
 
-                    let c_exec = CString::new(path.as_str()).unwrap();
-                    let mut c_args: Vec<CString> = args
-                        .iter()
-                        .map(|a| CString::new(a.as_str()).unwrap())
-                        .collect();
-                    c_args.insert(0, c_exec.clone());
+                    let c_exec = c_string(path.as_deref().unwrap_or_default());
+                    let c_args: Vec<CString> = argv.iter().map(|a| c_string(a)).collect();
 
                     let mut ptr_args: Vec<*const c_char> =
                         c_args.iter().map(|s| s.as_ptr()).collect();
                     ptr_args.push(std::ptr::null());
 
-                    let c_env: Vec<CString> = std::env::vars()
-                        .map(|(key, val)| CString::new(format!("{}={}", key, val)).unwrap())
-                        .collect();
+                    let c_env = environment(&assignments, shell);
                     let mut env_ptrs: Vec<*const c_char> =
                         c_env.iter().map(|env| env.as_ptr()).collect();
                     env_ptrs.push(std::ptr::null());
 
+                    let start = Times::now();
                     unsafe {
                         let pid = fork();
                         if pid == 0 {
-                            signal(SIGINT, SIG_DFL);
-                            signal(SIGQUIT, SIG_DFL);
+                            signals::reset(shell);
 
-                            setpgid(0, 0);
-
-                            tcsetpgrp(0, getpid());
+                            if shell.job_control {
+                                setpgid(0, 0);
+                                tcsetpgrp(0, getpid());
+                            }
 
-                            if let Err(e) = self.redirect() {
-                                eprintln!("Redirection error: {}", e);
+                            if let Err(e) = self.redirect(shell) {
+                                eprintln!("rush: {}", e);
                                 exit(1);
                             }
 
+                            if path.is_none() {
+                                let status = command_not_found(&argv, shell);
+                                let _ = std::io::stdout().flush();
+                                exit(status);
+                            }
+
                             execve(c_exec.as_ptr(), ptr_args.as_ptr(), env_ptrs.as_ptr());
-                            eprintln!("Execution failed");
-                            exit(1);
+                            let error = std::io::Error::last_os_error();
+                            eprintln!("rush: {}: {}", argv[0], error_message(&error));
+                            exit(if error.raw_os_error() == Some(ENOENT) {
+                                127
+                            } else {
+                                126
+                            });
                         } else if pid < 0 {
-                            eprintln!("Fork failed");
+                            let error = std::io::Error::last_os_error();
+                            eprintln!("rush: fork: {}", error_message(&error));
                             return 1;
                         }
 
+                        if !shell.job_control {
+                            let mut status = 0;
+                            waitpid(pid, &mut status, 0);
+                            return exit_status(status);
+                        }
+
                         let shell_pgrp = getpgrp();
 
                         setpgid(pid, pid);
@@ -207,11 +532,8 @@ impl Command {
                         let _ = tcsetpgrp(0, shell_pgrp);
                         ioctl(0, TIOCSPGRP, &shell_pgrp);
 
-                        if WIFEXITED(status) {
-                            WEXITSTATUS(status) as i32
-                        } else {
-                            1
-                        }
+                        report_time(&start, shell);
+                        exit_status(status)
                     }
                 }
             }
@@ -222,150 +544,2874 @@ impl Command {
                 operator,
             } => match operator {
                 Operator::Pipe => {
-                    let mut fds = [0; 2];
-                    unsafe {
-                        if pipe(fds.as_mut_ptr()) != 0 {
-                            eprintln!("Pipe creation failed");
-                            return 1;
-                        }
-                    }
-
-                    let (read_end, write_end) = (fds[0], fds[1]);
-                    let left_pid = unsafe { fork() };
-                    if left_pid == 0 {
-                        unsafe {
-                            close(read_end);
-                            dup2(write_end, 1);
-                            close(write_end);
-                            exit(left.execute());
-                        }
-                    }
+                    let mut stages = vec![];
+                    self.pipeline(&mut stages);
 
-                    let right_pid = unsafe { fork() };
-                    if right_pid == 0 {
-                        unsafe {
-                            close(write_end);
-                            dup2(read_end, 0);
-                            close(read_end);
-                            exit(right.execute());
+                    let pids = match spawn_pipeline(&stages, shell) {
+                        Ok(pids) => pids,
+                        Err(e) => {
+                            eprintln!("rush: {}", e);
+                            return 1;
                         }
-                    }
-
-                    unsafe {
-                        close(read_end);
-                        close(write_end);
-
-                        let mut status = 0;
-                        waitpid(left_pid, &mut status, 0);
-                        waitpid(right_pid, &mut status, 0);
+                    };
 
-                        WEXITSTATUS(status) as i32
-                    }
+                    wait_foreground(pids, shell)
                 }
                 Operator::And => {
-                    let left_code = left.execute();
-                    if left_code == 0 {
-                        right.execute()
+                    let condition = std::mem::replace(&mut shell.in_condition, true);
+                    let left_code = left.execute(shell);
+                    shell.in_condition = condition;
+                    if left_code == 0 && !shell.jumping() {
+                        right.execute(shell)
                     } else {
                         left_code
                     }
                 }
                 Operator::Or => {
-                    let left_code = left.execute();
-                    if left_code == 0 {
+                    let condition = std::mem::replace(&mut shell.in_condition, true);
+                    let left_code = left.execute(shell);
+                    shell.in_condition = condition;
+                    if left_code == 0 || shell.jumping() {
                         left_code
                     } else {
-                        right.execute()
+                        right.execute(shell)
                     }
                 }
                 Operator::Semicolon => {
-                    let _ = left.execute();
-                    right.execute()
-                }
-                Operator::Background => unsafe {
-                    let pid = fork();
-
-                    if pid < 0 {
-                        eprintln!("Fork failed for background process");
-                        1
-                    } else if pid == 0 {
-                        let exit_code = left.execute();
-                        exit(exit_code);
-                    } else {
-                        right.execute()
+                    let status = left.execute(shell);
+                    match shell.jumping() {
+                        true => status,
+                        false => right.execute(shell),
                     }
-                },
+                }
             },
 
-            Command::Group { group } => group.execute(),
-        }
-    }
+            // Starting the job succeeds whatever its outcome, which only
+            // `wait` reports, so `$?` is 0 for the commands that follow.
+            Command::Background { command } => {
+                command.spawn_background(shell);
+                0
+            }
 
-    pub fn is_builtin(&self) -> bool {
-        match self {
-            Command::Simple { executable, .. } => {
-                matches!(executable.as_str(), "cd" | "echo" | "exit" | "type")
+            Command::Group { group } => match spawn_pipeline(&[group.as_ref()], shell) {
+                Ok(pids) => wait_foreground(pids, shell),
+                Err(e) => {
+                    eprintln!("rush: {}", e);
+                    1
+                }
+            },
+
+            Command::BraceGroup { group } => group.execute(shell),
+
+            Command::Function { name, body } => {
+                shell.functions.insert(name.clone(), body.as_ref().clone());
+                0
             }
-            _ => false,
-        }
-    }
 
-    fn execute_builtin(&self) -> i32 {
-        match self {
-            Command::Simple {
-                executable, args, ..
-            } => match executable.as_str() {
-                "cd" => {
-                    let path = args.get(0).map(|s| s.as_str()).unwrap_or("~");
-                    match std::env::set_current_dir(path) {
-                        Ok(_) => 0,
-                        Err(e) => {
-                            eprintln!("cd: {}", e);
-                            1
-                        }
+            // The negated pipeline is a condition, so its failure doesn't run
+            // the `ERR` trap.
+            Command::Not { command } => {
+                let condition = std::mem::replace(&mut shell.in_condition, true);
+                let status = command.execute(shell);
+                shell.in_condition = condition;
+                if status == 0 {
+                    1
+                } else {
+                    0
+                }
+            }
+
+            Command::Case { word, clauses } => {
+                let word: Vec<char> = expand_string(word, shell).chars().collect();
+                let mut status = 0;
+                let mut matched = false;
+
+                for clause in clauses {
+                    matched = matched
+                        || clause.patterns.iter().any(|pattern| {
+                            let pattern = expand_pattern(pattern, shell);
+                            glob::matches(&pattern, &word, &shell.options)
+                        });
+                    if !matched {
+                        continue;
+                    }
+
+                    if let Some(body) = &clause.body {
+                        status = body.execute(shell);
+                    }
+                    if shell.jumping() {
+                        break;
+                    }
+                    match clause.terminator {
+                        CaseTerminator::Break => break,
+                        CaseTerminator::Fallthrough => {}
+                        CaseTerminator::Continue => matched = false,
+                    }
+                }
+                status
+            }
+
+            Command::Select { name, words, body } => match loop_words(words, shell) {
+                Ok(words) => run_select(name, &words, body, shell),
+                Err(e) => {
+                    eprintln!("rush: {}", e);
+                    1
+                }
+            },
+
+            Command::For { name, words, body } => {
+                let words = match loop_words(words, shell) {
+                    Ok(words) => words,
+                    Err(e) => {
+                        eprintln!("rush: {}", e);
+                        return 1;
+                    }
+                };
+
+                let mut status = 0;
+                shell.loops += 1;
+                for word in words {
+                    if let Err(e) = shell.variables.set(name, &word) {
+                        eprintln!("rush: {}", e);
+                        status = 1;
+                        break;
+                    }
+                    status = body.execute(shell);
+                    if loop_ends(shell) {
+                        break;
                     }
                 }
+                shell.loops -= 1;
+                status
+            }
 
-                "echo" => {
-                    println!("{}", args.join(" "));
-                    0
+            // Conditions don't run the `ERR` trap when they fail.
+            Command::While {
+                condition,
+                body,
+                until,
+            } => {
+                let mut status = 0;
+                shell.loops += 1;
+                loop {
+                    let in_condition = std::mem::replace(&mut shell.in_condition, true);
+                    let result = condition.execute(shell);
+                    shell.in_condition = in_condition;
+                    if loop_ends(shell) || (result == 0) == *until {
+                        break;
+                    }
+
+                    status = body.execute(shell);
+                    if loop_ends(shell) {
+                        break;
+                    }
                 }
+                shell.loops -= 1;
+                status
+            }
 
-                "exit" => {
-                    unsafe { exit(0) };
+            Command::If {
+                branches,
+                otherwise,
+            } => {
+                for (condition, body) in branches {
+                    let in_condition = std::mem::replace(&mut shell.in_condition, true);
+                    let result = condition.execute(shell);
+                    shell.in_condition = in_condition;
+                    if shell.jumping() {
+                        return result;
+                    }
+                    if result == 0 {
+                        return body.execute(shell);
+                    }
                 }
+                match otherwise {
+                    Some(otherwise) => otherwise.execute(shell),
+                    None => 0,
+                }
+            }
+
+            // The redirections are in place in the shell for as long as the
+            // command runs, like for builtins.
+            Command::Redirected { command, redirects } => {
+                let Some(saved_fds) = self.redirect_saving(redirects, shell) else {
+                    return 1;
+                };
+                let status = command.execute(shell);
+                restore_fds(saved_fds);
+                status
+            }
 
-                "type" => {
-                    eprint!("Not implemented");
+            Command::Coproc { name, command } => match spawn_coproc(name, command, shell) {
+                Ok(pid) => {
+                    let id = shell.jobs.add(pid, vec![pid]);
+                    if shell.job_control {
+                        eprintln!("[{}] {}", id, pid);
+                    }
                     0
                 }
-
-                _ => panic!(),
+                Err(e) => {
+                    eprintln!("rush: {}", e);
+                    1
+                }
             },
 
-            _ => panic!(),
+            Command::Timed { command } => {
+                let start = Times::now();
+                let status = command.execute(shell);
+                let times = Times::now().since(&start);
+
+                let format = shell.variables.get("TIMEFORMAT").unwrap_or(DEFAULT_FORMAT);
+                if !format.is_empty() {
+                    eprintln!("{}", times.format(format));
+                }
+                status
+            }
         }
     }
 
-    fn path(&self) -> String {
+    // Collects the stages of a pipeline from left to right.
+    fn pipeline<'a>(&'a self, stages: &mut Vec<&'a Command>) {
         match self {
-            Command::Simple { executable, .. } => {
-                let path = std::env::var("PATH").unwrap_or("".to_string());
-                let paths: Vec<&str> = path.split(':').collect();
+            Command::Binary {
+                left,
+                right,
+                operator: Operator::Pipe,
+            } => {
+                left.pipeline(stages);
+                right.pipeline(stages);
+            }
+            _ => stages.push(self),
+        }
+    }
 
-                for path in paths {
-                    let executable_path = path.to_owned() + "/" + executable.as_str();
+    // Starts the command as a job without waiting for it, so that `$!`
+    // refers to the last stage of a pipeline.
+    fn spawn_background(&self, shell: &mut Shell) {
+        let mut stages = vec![];
+        self.pipeline(&mut stages);
 
-                    let can_execute = unsafe { access(executable_path.as_ptr() as *const _, X_OK) };
-                    if can_execute == 0 {
-                        return executable_path;
-                    }
+        match spawn_pipeline(&stages, shell) {
+            Ok(pids) => {
+                let last = *pids.last().unwrap();
+                let id = shell.jobs.add(pids[0], pids);
+                if shell.job_control {
+                    eprintln!("[{}] {}", id, last);
                 }
+            }
+            Err(e) => eprintln!("rush: {}", e),
+        }
+    }
+}
+
+// Runs the body of `select` for each choice read from the standard input.
+// The menu of words goes to stderr, shown again for an empty line, and
+// `$PS3` prompts for the choice, which is kept in `$REPLY` while `name`
+// becomes the chosen word, or is emptied when the choice isn't a number of
+// the menu. The loop ends at the end of the input or with `break`.
+fn run_select(name: &str, words: &[String], body: &Command, shell: &mut Shell) -> i32 {
+    if words.is_empty() {
+        return 0;
+    }
+
+    let menu: String = words
+        .iter()
+        .enumerate()
+        .map(|(i, word)| format!("{}) {}\n", i + 1, word))
+        .collect();
+
+    let mut status = 0;
+    let mut show_menu = true;
+    shell.loops += 1;
+    loop {
+        if show_menu {
+            eprint!("{}", menu);
+        }
+        eprint!("{}", shell.variables.get("PS3").unwrap_or("#? "));
+
+        let Some(reply) = read_line() else {
+            eprintln!();
+            break;
+        };
+        show_menu = reply.is_empty();
+        if reply.is_empty() {
+            continue;
+        }
+
+        let choice = reply
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| words.get(n.checked_sub(1)?));
+        let result = shell.variables.set("REPLY", &reply).and_then(|_| {
+            shell
+                .variables
+                .set(name, choice.map_or("", |word| word.as_str()))
+        });
+        if let Err(e) = result {
+            eprintln!("rush: {}", e);
+            status = 1;
+            break;
+        }
+
+        status = body.execute(shell);
+        if loop_ends(shell) {
+            break;
+        }
+    }
+    shell.loops -= 1;
+    status
+}
 
-                executable.clone()
+// The words a `for` or `select` loop goes through, the positional
+// parameters when the loop has no `in`.
+fn loop_words(words: &Option<Vec<String>>, shell: &mut Shell) -> Result<Vec<String>, String> {
+    match words {
+        Some(words) => {
+            let mut fields = vec![];
+            for word in words {
+                fields.extend(expand_word(word, shell)?);
             }
+            Ok(fields)
+        }
+        None => Ok(shell.positional.clone()),
+    }
+}
+
+// Called after a part of a loop ran: whether the loop ends, taking the
+// `break` or `continue` aimed at it into account. A `continue` for an
+// outer loop ends this one too.
+fn loop_ends(shell: &mut Shell) -> bool {
+    if shell.returning.is_some() {
+        return true;
+    }
+    if shell.breaking > 0 {
+        shell.breaking -= 1;
+        return true;
+    }
+    if shell.continuing > 0 {
+        shell.continuing -= 1;
+        return shell.continuing > 0;
+    }
+    false
+}
+
+// Reads a line from the standard input one byte at a time, leaving the
+// rest of the input to later commands, or `None` at the end of the input.
+fn read_line() -> Option<String> {
+    let mut line = Vec::new();
+    let mut byte = 0u8;
+    loop {
+        if unsafe { libc::read(0, &mut byte as *mut u8 as *mut _, 1) } != 1 {
+            return match line.is_empty() {
+                true => None,
+                false => Some(String::from_utf8_lossy(&line).into_owned()),
+            };
+        }
+        if byte == b'\n' {
+            return Some(String::from_utf8_lossy(&line).into_owned());
+        }
+        line.push(byte);
+    }
+}
+
+// `break` leaves `n` enclosing loops and `continue` resumes the `n`th one,
+// by default the innermost. Both are only meaningful inside a loop.
+fn builtin_break(name: &str, args: &[String], shell: &mut Shell) -> i32 {
+    let args = end_of_options(args);
+    let count = match args.first().map(|arg| arg.parse::<i64>()) {
+        None => 1,
+        Some(Ok(count)) if count >= 1 => count as usize,
+        Some(Ok(_)) => {
+            eprintln!("rush: {}: {}: loop count out of range", name, args[0]);
+            return 1;
+        }
+        Some(Err(_)) => {
+            eprintln!("rush: {}: {}: numeric argument required", name, args[0]);
+            return 2;
+        }
+    };
+
+    if shell.loops == 0 {
+        eprintln!(
+            "rush: {}: only meaningful in a `for', `while', or `until' loop",
+            name
+        );
+        return 0;
+    }
+
+    let count = count.min(shell.loops);
+    match name {
+        "break" => shell.breaking = count,
+        _ => shell.continuing = count,
+    }
+    0
+}
+
+// `return` leaves the running function or sourced script with the status
+// given, `$?` by default.
+fn builtin_return(args: &[String], shell: &mut Shell) -> i32 {
+    let args = end_of_options(args);
+    let status = match args.first().map(|arg| arg.parse::<i64>()) {
+        None => shell.last_status,
+        Some(Ok(status)) => (status & 0xff) as i32,
+        Some(Err(_)) => {
+            eprintln!("rush: return: {}: numeric argument required", args[0]);
+            2
+        }
+    };
+
+    if shell.returnable == 0 {
+        eprintln!("rush: return: can only `return' from a function or sourced script");
+        return 1;
+    }
+    shell.returning = Some(status);
+    status
+}
+
+// Prints the times of a foreground job that ran for longer than
+// `$REPORTTIME` seconds, like zsh, in the format `time` uses. Only the
+// interactive shell reports them, not the subshells of a pipeline.
+fn report_time(start: &Times, shell: &Shell) {
+    let threshold = match shell.variables.get("REPORTTIME").map(str::parse::<f64>) {
+        Some(Ok(threshold)) if threshold > 0.0 => threshold,
+        _ => return,
+    };
+
+    let times = Times::now().since(start);
+    if times.real.as_secs_f64() > threshold {
+        let format = shell.variables.get("TIMEFORMAT").unwrap_or(DEFAULT_FORMAT);
+        eprintln!("{}", times.format(format));
+    }
+}
+
+// Waits for every stage of a foreground pipeline, handing it the terminal
+// meanwhile, and returns the status of the last stage whatever the order
+// the stages finish in. With `pipefail`, it is that of the last stage that
+// failed instead.
+fn wait_foreground(pids: Vec<pid_t>, shell: &Shell) -> i32 {
+    let start = Times::now();
+    unsafe {
+        let shell_pgrp = getpgrp();
+        if shell.job_control {
+            tcsetpgrp(0, pids[0]);
+        }
+
+        let statuses: Vec<i32> = pids
+            .iter()
+            .map(|&pid| {
+                let mut status = 0;
+                waitpid(pid, &mut status, 0);
+                exit_status(status)
+            })
+            .collect();
 
-            _ => "".to_string(),
+        if shell.job_control {
+            tcsetpgrp(0, shell_pgrp);
+            report_time(&start, shell);
         }
+
+        if shell.options.enabled("pipefail") {
+            statuses
+                .into_iter()
+                .rev()
+                .find(|&status| status != 0)
+                .unwrap_or(0)
+        } else {
+            *statuses.last().unwrap()
+        }
+    }
+}
+
+// Forks one process per stage, connected by pipes and sharing the process
+// group of the first stage, and returns their pids without waiting.
+fn spawn_pipeline(stages: &[&Command], shell: &mut Shell) -> Result<Vec<pid_t>, String> {
+    let mut pids: Vec<pid_t> = vec![];
+    let mut input: Option<c_int> = None;
+
+    for (i, stage) in stages.iter().enumerate() {
+        let last = i + 1 == stages.len();
+
+        // Pipes are close-on-exec, so that a copy a stage does not use can
+        // never keep a reader from seeing the end of its input. The copies
+        // made with `dup2` onto 0 and 1 stay open across `execve`.
+        let mut fds = [0; 2];
+        if !last && unsafe { pipe2(fds.as_mut_ptr(), O_CLOEXEC) } != 0 {
+            let error = std::io::Error::last_os_error();
+            if let Some(read_end) = input {
+                unsafe { close(read_end) };
+            }
+            abandon(&pids);
+            return Err(format!("pipe: {}", error_message(&error)));
+        }
+
+        let pgid = pids.first().copied().unwrap_or(0);
+        let pid = unsafe { fork() };
+        if pid < 0 {
+            let error = std::io::Error::last_os_error();
+            unsafe {
+                if let Some(read_end) = input {
+                    close(read_end);
+                }
+                if !last {
+                    close(fds[0]);
+                    close(fds[1]);
+                }
+            }
+            abandon(&pids);
+            return Err(format!("fork: {}", error_message(&error)));
+        } else if pid == 0 {
+            unsafe {
+                if shell.job_control {
+                    setpgid(0, pgid);
+                }
+                shell.job_control = false;
+                shell.enter_subshell();
+
+                if let Some(read_end) = input {
+                    dup2(read_end, 0);
+                    close(read_end);
+                }
+                if !last {
+                    close(fds[0]);
+                    dup2(fds[1], 1);
+                    close(fds[1]);
+                }
+
+                // Every stage, builtins included, runs in its own subshell,
+                // so `echo hi | read x` leaves `x` unset in the shell itself.
+                let status = stage.execute(shell);
+                let _ = std::io::stdout().flush();
+                exit(status);
+            }
+        }
+
+        unsafe {
+            if shell.job_control {
+                setpgid(pid, if pgid == 0 { pid } else { pgid });
+            }
+            if let Some(read_end) = input {
+                close(read_end);
+            }
+            if !last {
+                close(fds[1]);
+                input = Some(fds[0]);
+            }
+        }
+
+        pids.push(pid);
+    }
+
+    Ok(pids)
+}
+
+// Starts a coprocess with its standard input and output connected to pipes
+// the shell keeps open: `NAME[0]` reads what it writes and `NAME[1]` writes
+// to it. Its pid is stored in `NAME_PID`.
+fn spawn_coproc(name: &str, command: &Command, shell: &mut Shell) -> Result<pid_t, String> {
+    let mut input = [0; 2];
+    let mut output = [0; 2];
+
+    unsafe {
+        if pipe2(input.as_mut_ptr(), O_CLOEXEC) != 0 {
+            let error = std::io::Error::last_os_error();
+            return Err(format!("pipe: {}", error_message(&error)));
+        }
+        if pipe2(output.as_mut_ptr(), O_CLOEXEC) != 0 {
+            let error = std::io::Error::last_os_error();
+            close(input[0]);
+            close(input[1]);
+            return Err(format!("pipe: {}", error_message(&error)));
+        }
+
+        let pid = fork();
+        if pid < 0 {
+            let error = std::io::Error::last_os_error();
+            for fd in input.iter().chain(&output) {
+                close(*fd);
+            }
+            return Err(format!("fork: {}", error_message(&error)));
+        } else if pid == 0 {
+            if shell.job_control {
+                setpgid(0, 0);
+            }
+            shell.job_control = false;
+            shell.enter_subshell();
+
+            dup2(input[0], 0);
+            dup2(output[1], 1);
+            for fd in input.iter().chain(&output) {
+                close(*fd);
+            }
+
+            let status = command.execute(shell);
+            let _ = std::io::stdout().flush();
+            exit(status);
+        }
+
+        if shell.job_control {
+            setpgid(pid, pid);
+        }
+        close(input[0]);
+        close(output[1]);
+
+        let fds = vec![output[0].to_string(), input[1].to_string()];
+        shell.variables.set_array(name, fds)?;
+        shell
+            .variables
+            .set(&format!("{}_PID", name), &pid.to_string())?;
+        Ok(pid)
+    }
+}
+
+// Stops and reaps the stages already started when a pipeline can't be
+// completed, so that none of them is left waiting on the others.
+fn abandon(pids: &[pid_t]) {
+    for &pid in pids {
+        unsafe { kill(pid, SIGTERM) };
+        let mut status = 0;
+        waitpid(pid, &mut status, 0);
+    }
+}
+
+fn expand_argv(
+    executable: &str,
+    args: &[String],
+    shell: &mut Shell,
+) -> Result<Vec<String>, String> {
+    let mut argv = expand_word(executable, shell)?;
+    for arg in args {
+        if argv.first().is_some_and(|name| is_declaration(name)) && is_assignment(arg) {
+            // The elements of an array literal are expanded by the builtin.
+            match array_literal(arg) {
+                Some(_) => argv.push(arg.clone()),
+                None => argv.push(expand_assignment(arg, shell)),
+            }
+        } else {
+            argv.extend(expand_word(arg, shell)?);
+        }
+    }
+
+    Ok(argv)
+}
+
+pub fn is_builtin(name: &str) -> bool {
+    matches!(
+        name,
+        "." | ":"
+            | "alias"
+            | "break"
+            | "cd"
+            | "continue"
+            | "declare"
+            | "echo"
+            | "exec"
+            | "exit"
+            | "export"
+            | "fc"
+            | "help"
+            | "local"
+            | "mapfile"
+            | "pwd"
+            | "read"
+            | "readarray"
+            | "return"
+            | "set"
+            | "shopt"
+            | "source"
+            | "trap"
+            | "type"
+            | "unalias"
+            | "unset"
+            | "wait"
+    )
+}
+
+// The reserved words, which `type` reports as keywords.
+const KEYWORDS: [&str; 19] = [
+    "!", "{", "}", "case", "coproc", "do", "done", "elif", "else", "esac", "fi", "for", "if", "in",
+    "select", "then", "time", "until", "while",
+];
+
+// Builtins whose `NAME=value` arguments are expanded like assignments.
+fn is_declaration(name: &str) -> bool {
+    matches!(name, "declare" | "export" | "local")
+}
+
+fn execute_builtin(argv: &[String], shell: &mut Shell) -> i32 {
+    let args = &argv[1..];
+
+    match argv[0].as_str() {
+        "cd" => builtin_cd(args, shell),
+
+        "echo" => builtin_echo(args, shell),
+
+        "exit" => {
+            let args = end_of_options(args);
+            let status = match args.first() {
+                Some(arg) => match arg.parse::<i32>() {
+                    Ok(_) if args.len() > 1 => {
+                        eprintln!("rush: exit: too many arguments");
+                        return 1;
+                    }
+                    Ok(status) => status & 0xff,
+                    Err(_) => {
+                        eprintln!("rush: exit: {}: numeric argument required", arg);
+                        2
+                    }
+                },
+                None => shell.last_status,
+            };
+
+            unsafe { exit(status) };
+        }
+
+        "export" => {
+            let args = match options("export", args, "p", "[-p] [name[=value] ...]") {
+                Ok((_, args)) => args,
+                Err(status) => return status,
+            };
+
+            if args.is_empty() {
+                let mut output = String::new();
+                for (name, value) in shell.variables.environment() {
+                    let _ = writeln!(output, "export {}=\"{}\"", name, value);
+                }
+                return write_output("export", &output);
+            }
+
+            let mut status = 0;
+            for arg in args {
+                match split_assignment(arg) {
+                    Some((name, append, value)) => {
+                        let result = match append {
+                            true => shell.variables.append(name, value),
+                            false => shell.variables.set(name, value),
+                        };
+                        if let Err(e) = result {
+                            eprintln!("rush: export: {}", e);
+                            return 1;
+                        }
+                        shell.variables.export(name);
+                    }
+                    None if is_name(arg) => shell.variables.export(arg),
+                    None => {
+                        eprintln!("rush: export: `{}': not a valid identifier", arg);
+                        status = 1;
+                    }
+                }
+            }
+            status
+        }
+
+        "declare" => builtin_declare("declare", args, false, shell),
+
+        "local" => {
+            if !shell.variables.in_function() {
+                eprintln!("rush: local: can only be used in a function");
+                return 1;
+            }
+            builtin_declare("local", args, true, shell)
+        }
+
+        "pwd" => builtin_pwd(args, shell),
+
+        "fc" => builtin_fc(args, shell),
+
+        "break" | "continue" => builtin_break(&argv[0], args, shell),
+
+        "return" => builtin_return(args, shell),
+
+        "read" => builtin_read(args, shell),
+
+        "mapfile" | "readarray" => builtin_mapfile(&argv[0], args, shell),
+
+        "set" => builtin_set(args, shell),
+
+        "shopt" => builtin_shopt(args, shell),
+
+        "source" | "." => builtin_source(&argv[0], args, shell),
+
+        "trap" => builtin_trap(args, shell),
+
+        // Does nothing beyond the expansions and redirections of the command.
+        ":" => 0,
+
+        "alias" => builtin_alias(args, shell),
+
+        "unalias" => builtin_unalias(args, shell),
+
+        "wait" => builtin_wait(args, shell),
+
+        "type" => builtin_type(args, shell),
+
+        "help" => builtin_help(args, shell),
+
+        "unset" => builtin_unset(args, shell),
+
+        _ => panic!(),
+    }
+}
+
+// `cd -` returns to `$OLDPWD` and relative names not starting with `.` are
+// looked up in `$CDPATH`, both printing the new directory. By default `$PWD`
+// keeps symbolic links, with `..` removing the last component of the path
+// rather than going to the parent of where a link points, while `-P` makes
+// it the physical path.
+fn builtin_cd(args: &[String], shell: &mut Shell) -> i32 {
+    let (flags, args) = match options("cd", args, "LP", "[-L|-P] [dir]") {
+        Ok(options) => options,
+        Err(status) => return status,
+    };
+    let physical = flags.ends_with('P');
+    if args.len() > 1 {
+        eprintln!("rush: cd: too many arguments");
+        return 1;
+    }
+
+    let (mut path, mut print) = match args.first().map(|arg| arg.as_str()) {
+        Some("-") => match shell.variables.get("OLDPWD") {
+            Some(old) => (old.to_string(), true),
+            None => {
+                eprintln!("rush: cd: OLDPWD not set");
+                return 1;
+            }
+        },
+        Some(path) => (path.to_string(), false),
+        None => match shell.variables.get("HOME") {
+            Some(home) => (home.to_string(), false),
+            None => {
+                eprintln!("rush: cd: HOME not set");
+                return 1;
+            }
+        },
+    };
+
+    if path.is_empty() {
+        return 0;
+    }
+
+    let relative =
+        !path.starts_with('/') && !matches!(path.split('/').next(), Some(".") | Some(".."));
+    if relative && args.first().is_some_and(|arg| arg != "-") {
+        let cdpath = shell.variables.get("CDPATH").unwrap_or("");
+        let found = cdpath
+            .split(':')
+            .filter(|dir| !dir.is_empty())
+            .find_map(|dir| {
+                let candidate = format!("{}/{}", dir.trim_end_matches('/'), path);
+                std::path::Path::new(&candidate)
+                    .is_dir()
+                    .then_some(candidate)
+            });
+        if let Some(candidate) = found {
+            path = candidate;
+            print = true;
+        }
+    }
+
+    let old = match shell.variables.get("PWD") {
+        Some(pwd) if pwd.starts_with('/') => pwd.to_string(),
+        _ => std::env::current_dir()
+            .map(|cwd| cwd.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+
+    let logical = match path.starts_with('/') {
+        true => normalize(&path),
+        false => normalize(&format!("{}/{}", old, path)),
+    };
+    let target = if physical { &path } else { &logical };
+
+    if let Err(e) = std::env::set_current_dir(target) {
+        eprintln!(
+            "rush: cd: {}: {}",
+            args.first().unwrap_or(&path),
+            error_message(&e)
+        );
+        return 1;
+    }
+
+    let pwd = match physical {
+        true => std::env::current_dir()
+            .map(|cwd| cwd.to_string_lossy().into_owned())
+            .unwrap_or(logical),
+        false => logical,
+    };
+    let _ = shell.variables.set("OLDPWD", &old);
+    let _ = shell.variables.set("PWD", &pwd);
+
+    match print {
+        true => write_output("cd", &format!("{}\n", pwd)),
+        false => 0,
+    }
+}
+
+// Prints `$PWD`, or the physical directory with `-P` or when `$PWD` isn't
+// an absolute name of the current directory.
+fn builtin_pwd(args: &[String], shell: &mut Shell) -> i32 {
+    let physical = match options("pwd", args, "LP", "[-L|-P]") {
+        Ok((flags, _)) => flags.ends_with('P'),
+        Err(status) => return status,
+    };
+
+    let cwd = match std::env::current_dir() {
+        Ok(cwd) => cwd,
+        Err(e) => {
+            eprintln!("rush: pwd: {}", error_message(&e));
+            return 1;
+        }
+    };
+
+    let pwd = shell.variables.get("PWD").filter(|pwd| {
+        !physical
+            && pwd.starts_with('/')
+            && std::fs::canonicalize(pwd).is_ok_and(|resolved| resolved == cwd)
+    });
+    let output = match pwd {
+        Some(pwd) => format!("{}\n", pwd),
+        None => format!("{}\n", cwd.to_string_lossy()),
+    };
+    write_output("pwd", &output)
+}
+
+// `fc -l` lists commands from the history, `fc -s [old=new] [command]` runs
+// one again with `old` replaced by `new`, and `fc [first [last]]` edits
+// commands with `-e`, `$FCEDIT` or `$EDITOR` before running them. Commands
+// are chosen by number, by offset from the end when negative, or by the
+// start of their text.
+fn builtin_fc(args: &[String], shell: &mut Shell) -> i32 {
+    let synopsis = "[-e ename] [-lnr] [first] [last] or fc -s [pat=rep] [command]";
+    let mut history = input_history_entries();
+    // The line running `fc` was added to the history before it ran.
+    if shell.job_control {
+        history.pop();
+    }
+
+    let (mut list, mut numbers, mut reverse, mut substitute) = (false, true, false, false);
+    let mut editor = None;
+    let mut operands = args;
+    while let Some(arg) = operands.first() {
+        if arg == "--" {
+            operands = &operands[1..];
+            break;
+        }
+        if !arg.starts_with('-') || arg.len() == 1 || arg.parse::<i64>().is_ok() {
+            break;
+        }
+        operands = &operands[1..];
+
+        for flag in arg.chars().skip(1) {
+            match flag {
+                'l' => list = true,
+                'n' => numbers = false,
+                'r' => reverse = true,
+                's' => substitute = true,
+                'e' => match operands.first() {
+                    Some(name) => {
+                        editor = Some(name.clone());
+                        operands = &operands[1..];
+                    }
+                    None => return usage("fc", "", synopsis),
+                },
+                _ => return usage("fc", &format!("-{}", flag), synopsis),
+            }
+        }
+    }
+
+    let find = |spec: &str| match spec.parse::<i64>() {
+        Ok(offset) if offset < 0 => history.len().checked_sub(offset.unsigned_abs() as usize),
+        Ok(number) => history.iter().position(|&(n, _)| n as i64 == number),
+        Err(_) => history.iter().rposition(|(_, line)| line.starts_with(spec)),
+    };
+    let out_of_range = || {
+        eprintln!("rush: fc: history specification out of range");
+        1
+    };
+
+    if substitute {
+        let (replacement, operands) = match operands.first() {
+            Some(arg) if arg.contains('=') => (arg.split_once('='), &operands[1..]),
+            _ => (None, operands),
+        };
+        let Some(index) = find(operands.first().map_or("-1", |spec| spec.as_str())) else {
+            return out_of_range();
+        };
+
+        let mut command = history[index].1.clone();
+        if let Some((old, new)) = replacement.filter(|(old, _)| !old.is_empty()) {
+            command = command.replace(old, new);
+        }
+        write_output("fc", &format!("{}\n", command));
+        input_history(&command);
+        return shell.eval(&command);
+    }
+
+    let default_first = if list { "-16" } else { "-1" };
+    let first = operands.first().map_or(default_first, |spec| spec.as_str());
+    let last = operands
+        .get(1)
+        .map_or(if list { "-1" } else { first }, |spec| spec.as_str());
+    let first = match find(first) {
+        Some(index) => index,
+        None if list && operands.is_empty() => 0,
+        None => return out_of_range(),
+    };
+    let Some(last) = find(last) else {
+        return out_of_range();
+    };
+
+    let mut entries: Vec<&(usize, String)> = match first <= last {
+        true => history[first..=last].iter().collect(),
+        false => history[last..=first].iter().rev().collect(),
+    };
+    if reverse {
+        entries.reverse();
+    }
+
+    if list {
+        let mut output = String::new();
+        for (number, line) in entries {
+            let _ = match numbers {
+                true => writeln!(output, "{}\t {}", number, line),
+                false => writeln!(output, "\t {}", line),
+            };
+        }
+        return write_output("fc", &output);
+    }
+
+    let editor = editor
+        .or_else(|| shell.variables.get("FCEDIT").map(String::from))
+        .or_else(|| shell.variables.get("EDITOR").map(String::from))
+        .unwrap_or_else(|| "vi".to_string());
+    let text: String = entries
+        .iter()
+        .map(|(_, line)| format!("{}\n", line))
+        .collect();
+
+    // A file of our own that nobody can have created in its place, as for
+    // here-documents, but kept for the editor to open by name.
+    let mut template = *b"/tmp/rush-fc-XXXXXX\0";
+    let fd = unsafe { mkstemp(template.as_mut_ptr() as *mut c_char) };
+    if fd < 0 {
+        let error = std::io::Error::last_os_error();
+        eprintln!(
+            "rush: fc: cannot create temp file: {}",
+            error_message(&error)
+        );
+        return 1;
+    }
+    let path = String::from_utf8_lossy(&template[..template.len() - 1]).into_owned();
+    let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+    if let Err(e) = file.write_all(text.as_bytes()) {
+        eprintln!("rush: fc: {}: {}", path, error_message(&e));
+        let _ = std::fs::remove_file(&path);
+        return 1;
+    }
+    drop(file);
+
+    let status = shell.eval(&format!("{} {}", editor, path));
+    let edited = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+    if status != 0 {
+        return status;
+    }
+
+    match edited {
+        Ok(commands) => {
+            write_output("fc", &commands);
+            input_history(commands.trim_end());
+            shell.eval_script(&commands)
+        }
+        Err(e) => {
+            eprintln!("rush: fc: {}: {}", path, error_message(&e));
+            1
+        }
+    }
+}
+
+// Resolves `.` and `..` in an absolute path without following links.
+fn normalize(path: &str) -> String {
+    let mut components: Vec<&str> = vec![];
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    format!("/{}", components.join("/"))
+}
+
+// `-n` drops the final newline, `-e` enables backslash escapes and `-E`
+// disables them. Escapes are disabled by default unless `xpg_echo` is set.
+fn builtin_echo(args: &[String], shell: &Shell) -> i32 {
+    let mut newline = true;
+    let mut escapes = shell.options.enabled("xpg_echo");
+
+    // Only arguments made entirely of known flags are options.
+    let count = args
+        .iter()
+        .take_while(|arg| {
+            arg.len() > 1
+                && arg.starts_with('-')
+                && arg[1..].chars().all(|c| matches!(c, 'n' | 'e' | 'E'))
+        })
+        .count();
+    for flag in args[..count].iter().flat_map(|arg| arg[1..].chars()) {
+        match flag {
+            'n' => newline = false,
+            'e' => escapes = true,
+            _ => escapes = false,
+        }
+    }
+
+    // Arguments arrive fully expanded and split, so joining on single
+    // spaces keeps whatever spacing quoting preserved inside each one.
+    let mut output = args[count..].join(" ");
+    if escapes {
+        let (text, stop) = decode_escapes(&output, true);
+        output = text;
+        newline &= !stop;
+    }
+    if newline {
+        output.push('\n');
+    }
+    write_output("echo", &output)
+}
+
+// Performs an assignment word in the current shell: `NAME=value`,
+// `NAME[index]=value` or `NAME=(a b c)`, or their `+=` forms.
+fn assign(assignment: &str, shell: &mut Shell) -> Result<(), String> {
+    if let Some((name, append, elements)) = array_literal(assignment) {
+        let values = expand_array(elements, shell)?;
+        return match append {
+            true => shell.variables.append_array(name, values),
+            false => shell.variables.set_array(name, values),
+        };
+    }
+
+    let assignment = expand_assignment(assignment, shell);
+    // The bad substitution was reported, the caller fails the command.
+    if shell.bad_substitution {
+        return Ok(());
+    }
+    let (target, append, value) = split_assignment(&assignment).unwrap();
+    match (subscript(target), append) {
+        (Some((name, index)), append) => {
+            let index = arithmetic::evaluate(index, &shell.variables)?;
+            match append {
+                true => shell.variables.append_element(name, index, value),
+                false => shell.variables.set_element(name, index, value),
+            }
+        }
+        (None, true) => shell.variables.append(target, value),
+        (None, false) => shell.variables.set(target, value),
+    }
+}
+
+// Closes the process substitutions opened since `start` once the command
+// using them is done, and waits for their processes.
+fn close_substitutions(start: usize, shell: &mut Shell) {
+    for (pid, fd) in shell.substitutions.split_off(start) {
+        unsafe { close(fd) };
+        let mut status = 0;
+        waitpid(pid, &mut status, 0);
+    }
+}
+
+// Puts the descriptors saved around a builtin or function back in place.
+fn restore_fds(saved_fds: std::collections::HashMap<u32, c_int>) {
+    for (fd, saved_fd) in saved_fds {
+        unsafe {
+            if saved_fd == -1 {
+                close(fd as c_int);
+            } else {
+                dup2(saved_fd, fd as c_int);
+                close(saved_fd);
+            }
+        }
+    }
+}
+
+// The environment of an external command: the exported variables, with the
+// assignments preceding the command on top.
+fn environment(assignments: &[(String, String)], shell: &Shell) -> Vec<CString> {
+    shell
+        .variables
+        .environment()
+        .into_iter()
+        .filter(|(key, _)| !assignments.iter().any(|(name, _)| name == key))
+        .chain(assignments.iter().cloned())
+        .map(|(key, val)| c_string(&format!("{}={}", key, val)))
+        .collect()
+}
+
+// `exec` without a command applies its redirections to the shell itself,
+// which is how descriptors like `exec 3< file` stay open across commands.
+// With a command, the shell is replaced by it.
+fn builtin_exec(
+    command: &Command,
+    args: &[String],
+    assignments: &[(String, String)],
+    shell: &mut Shell,
+) -> i32 {
+    if let Err(e) = command.redirect(shell) {
+        eprintln!("rush: {}", e);
+        return 1;
+    }
+
+    let args = end_of_options(args);
+    if args.is_empty() {
+        return 0;
+    }
+
+    let Some(path) = path(&args[0], shell) else {
+        eprintln!("rush: exec: {}: not found", args[0]);
+        unsafe { exit(127) };
+    };
+
+    let c_exec = c_string(&path);
+    let c_args: Vec<CString> = args.iter().map(|a| c_string(a)).collect();
+    let mut ptr_args: Vec<*const c_char> = c_args.iter().map(|s| s.as_ptr()).collect();
+    ptr_args.push(std::ptr::null());
+
+    let c_env = environment(assignments, shell);
+    let mut env_ptrs: Vec<*const c_char> = c_env.iter().map(|env| env.as_ptr()).collect();
+    env_ptrs.push(std::ptr::null());
+
+    let _ = std::io::stdout().flush();
+    unsafe { execve(c_exec.as_ptr(), ptr_args.as_ptr(), env_ptrs.as_ptr()) };
+
+    let error = std::io::Error::last_os_error();
+    eprintln!("rush: exec: {}: {}", args[0], error_message(&error));
+    unsafe { exit(126) }
+}
+
+// Runs a function body with the arguments as positional parameters and a
+// scope of its own for local variables.
+fn call_function(body: &Command, argv: &[String], shell: &mut Shell) -> i32 {
+    let positional = std::mem::replace(&mut shell.positional, argv[1..].to_vec());
+    shell.variables.push_scope();
+    shell.returnable += 1;
+
+    let mut status = body.execute(shell);
+    if let Some(returned) = shell.returning.take() {
+        status = returned;
+    }
+
+    shell.returnable -= 1;
+    shell.variables.pop_scope();
+    shell.positional = positional;
+    status
+}
+
+// `declare` and `local`, which only differ in that `local` always creates
+// variables in the scope of the running function. `declare` does too when
+// used inside a function, like in bash.
+fn builtin_declare(name: &str, args: &[String], local: bool, shell: &mut Shell) -> i32 {
+    let local = local || shell.variables.in_function();
+    let mut print = false;
+    let mut attributes = vec![];
+    let mut operands = args;
+
+    while let Some(arg) = operands.first() {
+        let enable = match arg.chars().next() {
+            Some('-') if arg != "-" => true,
+            Some('+') if arg != "+" => false,
+            _ => break,
+        };
+        operands = &operands[1..];
+        if arg == "--" {
+            break;
+        }
+
+        for flag in arg.chars().skip(1) {
+            match flag {
+                'p' => print = true,
+                'a' if enable => attributes.push((flag, enable)),
+                'i' | 'r' | 'x' if enable || flag != 'r' => attributes.push((flag, enable)),
+                _ => {
+                    let option = format!("{}{}", &arg[..1], flag);
+                    return usage(name, &option, "[-aiprx] [name[=value] ...]");
+                }
+            }
+        }
+    }
+
+    let mut output = String::new();
+    if operands.is_empty() {
+        for (variable_name, variable) in shell.variables.list() {
+            let listed = attributes.iter().all(|&(flag, _)| match flag {
+                'a' => variable.is_array(),
+                'i' => variable.integer,
+                'r' => variable.readonly,
+                _ => variable.exported,
+            });
+            if listed {
+                output.push_str(&declaration(&variable_name, &variable));
+            }
+        }
+        return write_output(name, &output);
+    }
+
+    let mut status = 0;
+    for operand in operands {
+        let (variable_name, append, value) = match operand.split_once('=') {
+            Some((variable_name, value)) => match variable_name.strip_suffix('+') {
+                Some(variable_name) => (variable_name, true, Some(value)),
+                None => (variable_name, false, Some(value)),
+            },
+            None => (operand.as_str(), false, None),
+        };
+
+        if print {
+            match shell.variables.variable(variable_name) {
+                Some(variable) => output.push_str(&declaration(variable_name, variable)),
+                None => {
+                    eprintln!("rush: {}: {}: not found", name, variable_name);
+                    status = 1;
+                }
+            }
+            continue;
+        }
+
+        if !is_name(variable_name) {
+            eprintln!("rush: {}: `{}': not a valid identifier", name, operand);
+            status = 1;
+            continue;
+        }
+
+        let variable = shell.variables.declare(variable_name, local);
+        if variable.readonly && value.is_some() {
+            eprintln!("rush: {}: {}: readonly variable", name, variable_name);
+            status = 1;
+            continue;
+        }
+
+        // Attributes apply before the value so that `declare -i n=1+1`
+        // evaluates it, while `-r` only takes effect once it is set.
+        for &(flag, enable) in &attributes {
+            match flag {
+                'a' => variable.make_array(),
+                'i' => variable.integer = enable,
+                'x' => variable.exported = enable,
+                _ => {}
+            }
+        }
+
+        if let Some(value) = value {
+            let result = match value.strip_prefix('(').and_then(|v| v.strip_suffix(')')) {
+                Some(elements) => expand_array(elements, shell).and_then(|values| match append {
+                    true => shell.variables.append_array(variable_name, values),
+                    false => shell.variables.set_array(variable_name, values),
+                }),
+                None if append => shell.variables.append(variable_name, value),
+                None => shell.variables.set(variable_name, value),
+            };
+            if let Err(e) = result {
+                eprintln!("rush: {}: {}", name, e);
+                status = 1;
+                continue;
+            }
+        }
+
+        if attributes.contains(&('r', true)) {
+            shell.variables.declare(variable_name, local).readonly = true;
+        }
+    }
+
+    status.max(write_output(name, &output))
+}
+
+fn declaration(name: &str, variable: &Variable) -> String {
+    match variable.is_set() {
+        true => format!(
+            "declare {} {}={}\n",
+            variable.flags(),
+            name,
+            variable.literal()
+        ),
+        false => format!("declare {} {}\n", variable.flags(), name),
+    }
+}
+
+// `-t timeout` gives up after that many seconds, possibly fractional, with
+// status 142 and whatever was read so far assigned, and `-t 0` only tells
+// whether input is waiting. `-n nchars` returns after that many characters
+// without waiting for the end of the line, which a terminal is switched out
+// of line mode for. On a terminal, `-p prompt` is written to stderr first
+// and `-s` keeps the input from being echoed.
+fn builtin_read(args: &[String], shell: &mut Shell) -> i32 {
+    let synopsis = "[-rs] [-n nchars] [-p prompt] [-t timeout] [name ...]";
+    let mut raw = false;
+    let mut silent = false;
+    let mut count = None;
+    let mut prompt = None;
+    let mut timeout = None;
+    let mut names = args;
+    while let Some(arg) = names.first() {
+        if arg == "--" {
+            names = &names[1..];
+            break;
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            break;
+        }
+        names = &names[1..];
+
+        // The value of an option may be attached to it, as in `-n1`.
+        let mut flags = arg[1..].chars();
+        while let Some(flag) = flags.next() {
+            match flag {
+                'r' => raw = true,
+                's' => silent = true,
+                _ => {}
+            }
+            if matches!(flag, 'r' | 's') {
+                continue;
+            }
+            if !matches!(flag, 'n' | 'p' | 't') {
+                return usage("read", &format!("-{}", flag), synopsis);
+            }
+
+            let value = match flags.as_str() {
+                "" => match names.first() {
+                    Some(value) => {
+                        names = &names[1..];
+                        value.as_str()
+                    }
+                    None => return usage("read", "", synopsis),
+                },
+                attached => attached,
+            };
+            if flag == 'p' {
+                prompt = Some(value);
+            } else if flag == 'n' {
+                match value.parse::<usize>() {
+                    Ok(value) => count = Some(value),
+                    Err(_) => {
+                        eprintln!("rush: read: {}: invalid number", value);
+                        return 1;
+                    }
+                }
+            } else {
+                match value.parse::<f64>() {
+                    Ok(value) if value >= 0.0 && value.is_finite() => timeout = Some(value),
+                    _ => {
+                        eprintln!("rush: read: {}: invalid timeout specification", value);
+                        return 1;
+                    }
+                }
+            }
+            break;
+        }
+    }
+    if let Some(name) = names.iter().find(|name| !is_name(name)) {
+        eprintln!("rush: read: `{}': not a valid identifier", name);
+        return 1;
+    }
+
+    if timeout == Some(0.0) {
+        let mut fd = pollfd {
+            fd: 0,
+            events: POLLIN,
+            revents: 0,
+        };
+        return if unsafe { poll(&mut fd, 1, 0) } > 0 {
+            0
+        } else {
+            1
+        };
+    }
+    let deadline = timeout.map(|timeout| Instant::now() + Duration::from_secs_f64(timeout));
+
+    // The terminal settings are restored even when an interrupt ends the
+    // read, which is passed on once they are.
+    let mut terminal = None;
+    if (count.is_some() || silent) && unsafe { isatty(0) } == 1 {
+        unsafe {
+            let mut attributes: termios = std::mem::zeroed();
+            if tcgetattr(0, &mut attributes) == 0 {
+                let mut changed = attributes;
+                if count.is_some() {
+                    changed.c_lflag &= !ICANON;
+                    changed.c_cc[VMIN] = 1;
+                    changed.c_cc[VTIME] = 0;
+                }
+                if silent {
+                    changed.c_lflag &= !ECHO;
+                }
+                tcsetattr(0, TCSANOW, &changed);
+
+                READ_INTERRUPTED.store(false, Ordering::SeqCst);
+                let mut action: sigaction = std::mem::zeroed();
+                action.sa_sigaction = read_interrupt as *const () as usize;
+                sigemptyset(&mut action.sa_mask);
+                let mut previous: sigaction = std::mem::zeroed();
+                sigaction(SIGINT, &action, &mut previous);
+                terminal = Some((attributes, previous));
+            }
+        }
+    }
+
+    if let Some(prompt) = prompt {
+        if unsafe { isatty(0) } == 1 {
+            eprint!("{}", prompt);
+        }
+    }
+
+    // Bytes are read one at a time so that nothing past the line is consumed
+    // from a shared input. Characters escaped with a backslash are kept from
+    // acting as field delimiters.
+    let mut line = Vec::new();
+    let mut escaped = Vec::new();
+    let mut eof = true;
+    let mut timed_out = false;
+    let mut chars = 0;
+
+    loop {
+        if count.is_some_and(|count| chars >= count) {
+            eof = false;
+            break;
+        }
+
+        let mut byte = match read_byte(deadline) {
+            Ok(Some(byte)) => byte,
+            Ok(None) => break,
+            Err(()) => {
+                timed_out = true;
+                break;
+            }
+        };
+
+        let mut quoted = false;
+        if !raw && byte == b'\\' {
+            byte = match read_byte(deadline) {
+                Ok(Some(byte)) => byte,
+                Ok(None) => break,
+                Err(()) => {
+                    timed_out = true;
+                    break;
+                }
+            };
+            if byte == b'\n' {
+                continue;
+            }
+            quoted = true;
+        } else if byte == b'\n' {
+            eof = false;
+            break;
+        }
+
+        // The rest of a multibyte character comes with its first byte so
+        // that `-n` counts characters.
+        line.push(byte);
+        escaped.push(quoted);
+        for _ in 1..utf8_width(byte) {
+            match read_byte(deadline) {
+                Ok(Some(byte)) => {
+                    line.push(byte);
+                    escaped.push(quoted);
+                }
+                _ => break,
+            }
+        }
+        chars += 1;
+    }
+
+    if let Some((attributes, previous)) = terminal {
+        unsafe {
+            tcsetattr(0, TCSANOW, &attributes);
+            sigaction(SIGINT, &previous, std::ptr::null_mut());
+            if READ_INTERRUPTED.load(Ordering::SeqCst) {
+                raise(SIGINT);
+                return 130;
+            }
+        }
+    }
+
+    let line = String::from_utf8_lossy(&line).into_owned();
+    let chars: Vec<char> = line.chars().collect();
+    let escaped: Vec<bool> = line
+        .char_indices()
+        .map(|(offset, _)| escaped.get(offset).copied().unwrap_or(false))
+        .collect();
+
+    let mut status = match (timed_out, eof) {
+        (true, _) => 142,
+        (false, true) => 1,
+        (false, false) => 0,
+    };
+
+    if names.is_empty() {
+        if let Err(e) = shell.variables.set("REPLY", &line) {
+            eprintln!("rush: read: {}", e);
+            status = 1;
+        }
+    } else {
+        let fields = split_fields(&chars, |i| !escaped[i], &ifs(shell), names.len());
+
+        for (i, name) in names.iter().enumerate() {
+            let value: String = match fields.get(i) {
+                Some(range) => chars[range.clone()].iter().collect(),
+                None => String::new(),
+            };
+            if let Err(e) = shell.variables.set(name, &value) {
+                eprintln!("rush: read: {}", e);
+                status = 1;
+            }
+        }
+    }
+
+    status
+}
+
+// Set when an interrupt arrives during a `read` that changed the terminal
+// settings, which it is caught for so that they can be restored.
+static READ_INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn read_interrupt(_signum: c_int) {
+    READ_INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// Reads a byte of standard input, `None` at the end of input, and `Err`
+// once `deadline` passes without one.
+fn read_byte(deadline: Option<Instant>) -> Result<Option<u8>, ()> {
+    if let Some(deadline) = deadline {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let mut fd = pollfd {
+                fd: 0,
+                events: POLLIN,
+                revents: 0,
+            };
+            let milliseconds = remaining.as_micros().div_ceil(1000).min(c_int::MAX as u128);
+            match unsafe { poll(&mut fd, 1, milliseconds as c_int) } {
+                0 => return Err(()),
+                -1 if READ_INTERRUPTED.load(Ordering::SeqCst) => return Ok(None),
+                -1 if std::io::Error::last_os_error().raw_os_error() == Some(EINTR) => continue,
+                _ => break,
+            }
+        }
+    }
+
+    let mut byte = 0u8;
+    match unsafe { libc::read(0, &mut byte as *mut u8 as *mut _, 1) } {
+        1 => Ok(Some(byte)),
+        _ => Ok(None),
+    }
+}
+
+// How many bytes the UTF-8 character starting with `byte` takes.
+fn utf8_width(byte: u8) -> usize {
+    match byte.leading_ones() {
+        2 => 2,
+        3 => 3,
+        4 => 4,
+        _ => 1,
+    }
+}
+
+// `mapfile` and `readarray` read lines into an array, `MAPFILE` unless
+// named, keeping their newlines unless `-t` is given. `-n count` stops
+// after that many lines.
+fn builtin_mapfile(name: &str, args: &[String], shell: &mut Shell) -> i32 {
+    let synopsis = "[-t] [-n count] [array]";
+    let mut trim = false;
+    let mut count = 0;
+    let mut operands = args;
+    while let Some(arg) = operands.first() {
+        if arg == "--" {
+            operands = &operands[1..];
+            break;
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            break;
+        }
+        operands = &operands[1..];
+
+        for flag in arg.chars().skip(1) {
+            match flag {
+                't' => trim = true,
+                'n' => match operands.first().map(|value| value.parse::<usize>()) {
+                    Some(Ok(value)) => {
+                        count = value;
+                        operands = &operands[1..];
+                    }
+                    Some(Err(_)) => {
+                        eprintln!("rush: {}: {}: invalid line count", name, operands[0]);
+                        return 1;
+                    }
+                    None => return usage(name, "", synopsis),
+                },
+                _ => return usage(name, &format!("-{}", flag), synopsis),
+            }
+        }
+    }
+
+    let array = operands.first().map_or("MAPFILE", |array| array.as_str());
+    if !is_name(array) {
+        eprintln!("rush: {}: `{}': not a valid identifier", name, array);
+        return 1;
+    }
+
+    // Like `read`, bytes are read one at a time so that input past the last
+    // line wanted is left for the next command.
+    let mut lines = vec![];
+    let mut line = Vec::new();
+    let mut byte = 0u8;
+    while count == 0 || lines.len() < count {
+        if unsafe { libc::read(0, &mut byte as *mut u8 as *mut _, 1) } != 1 {
+            if !line.is_empty() {
+                lines.push(String::from_utf8_lossy(&line).into_owned());
+            }
+            break;
+        }
+
+        if byte != b'\n' || !trim {
+            line.push(byte);
+        }
+        if byte == b'\n' {
+            lines.push(String::from_utf8_lossy(&line).into_owned());
+            line.clear();
+        }
+    }
+
+    match shell.variables.set_array(array, lines) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("rush: {}: {}", name, e);
+            1
+        }
+    }
+}
+
+// Only sets the positional parameters, which `set --` clears.
+fn builtin_set(args: &[String], shell: &mut Shell) -> i32 {
+    if args.is_empty() {
+        let mut output = String::new();
+        for (name, variable) in shell.variables.list() {
+            let _ = match variable.is_array() {
+                _ if !variable.is_set() => continue,
+                true => writeln!(output, "{}={}", name, variable.literal()),
+                false => writeln!(output, "{}={}", name, variable.scalar()),
+            };
+        }
+        return write_output("set", &output);
+    }
+
+    let mut args = args;
+    let mut positional = false;
+    while let Some(arg) = args.first() {
+        match arg.as_str() {
+            "--" => {
+                args = &args[1..];
+                positional = true;
+                break;
+            }
+            "-o" | "+o" => {
+                let enable = arg == "-o";
+                match args.get(1) {
+                    Some(name) => {
+                        if let Err(e) = shell.options.set_set_option(name, enable) {
+                            eprintln!("rush: set: {}", e);
+                            return 1;
+                        }
+                        args = &args[2..];
+                    }
+                    None => return print_set_options(enable, shell),
+                }
+            }
+            arg if arg.len() > 1 && (arg.starts_with('-') || arg.starts_with('+')) => {
+                let enable = arg.starts_with('-');
+                for flag in arg.chars().skip(1) {
+                    match Options::short_name(flag) {
+                        Some(name) => {
+                            let _ = shell.options.set_set_option(name, enable);
+                        }
+                        None => {
+                            let option = format!("{}{}", &arg[..1], flag);
+                            return usage("set", &option, "[-n] [-o option] [--] [arg ...]");
+                        }
+                    }
+                }
+                args = &args[1..];
+            }
+            _ => {
+                positional = true;
+                break;
+            }
+        }
+    }
+
+    if positional {
+        shell.positional = args.to_vec();
+    }
+    0
+}
+
+// `set -o` lists the options with their state, `set +o` as the commands
+// that would restore them.
+fn print_set_options(table: bool, shell: &Shell) -> i32 {
+    let mut output = String::new();
+    for (name, enabled) in shell.options.set_options() {
+        let _ = match table {
+            true => writeln!(
+                output,
+                "{:<15}\t{}",
+                name,
+                if enabled { "on" } else { "off" }
+            ),
+            false => writeln!(output, "set {}o {}", if enabled { '-' } else { '+' }, name),
+        };
+    }
+    write_output("set", &output)
+}
+
+fn builtin_shopt(args: &[String], shell: &mut Shell) -> i32 {
+    let (flags, names) = match options("shopt", args, "su", "[-su] [optname ...]") {
+        Ok(options) => options,
+        Err(status) => return status,
+    };
+    let enable = match (flags.contains('s'), flags.contains('u')) {
+        (true, true) => {
+            eprintln!("rush: shopt: cannot set and unset shell options simultaneously");
+            return 1;
+        }
+        (true, false) => Some(true),
+        (false, true) => Some(false),
+        (false, false) => None,
+    };
+
+    let mut status = 0;
+    match enable {
+        Some(enabled) => {
+            for name in names {
+                if let Err(e) = shell.options.set_shopt(name, enabled) {
+                    eprintln!("rush: shopt: {}", e);
+                    status = 1;
+                }
+            }
+        }
+        None => {
+            let mut output = String::new();
+            for (name, enabled) in shell.options.shopt_options() {
+                if !names.is_empty() && !names.iter().any(|n| n == name) {
+                    continue;
+                }
+
+                let state = if enabled { "on" } else { "off" };
+                let _ = writeln!(output, "{:<15}\t{}", name, state);
+                if !enabled {
+                    status = 1;
+                }
+            }
+
+            status = status.max(write_output("shopt", &output));
+
+            for name in names {
+                if shell.options.shopt(name).is_none() {
+                    eprintln!("rush: shopt: {}: invalid shell option name", name);
+                    status = 1;
+                }
+            }
+        }
+    }
+
+    status
+}
+
+fn builtin_source(name: &str, args: &[String], shell: &mut Shell) -> i32 {
+    let args = match options(name, args, "", "filename [arguments]") {
+        Ok((_, args)) => args,
+        Err(status) => return status,
+    };
+    let path = match args.first() {
+        Some(path) => path,
+        None => {
+            eprintln!("rush: {}: filename argument required", name);
+            return usage(name, "", "filename [arguments]");
+        }
+    };
+
+    match std::fs::read_to_string(path) {
+        Ok(script) => {
+            // Arguments become the positional parameters of the script for
+            // as long as it runs.
+            let line = shell.variables.line();
+            let positional = if args.len() > 1 {
+                Some(std::mem::replace(&mut shell.positional, args[1..].to_vec()))
+            } else {
+                None
+            };
+
+            shell.returnable += 1;
+            let mut status = shell.eval_script(&script);
+            shell.returnable -= 1;
+            if let Some(returned) = shell.returning.take() {
+                status = returned;
+            }
+
+            shell.variables.set_line(line);
+            if let Some(positional) = positional {
+                shell.positional = positional;
+            }
+            status
+        }
+        Err(e) => {
+            eprintln!("rush: {}: {}: {}", name, path, error_message(&e));
+            1
+        }
+    }
+}
+
+// `alias name=value` defines an alias and `alias name` or `alias` alone
+// print aliases the way they can be defined again.
+fn builtin_alias(args: &[String], shell: &mut Shell) -> i32 {
+    let definition =
+        |name: &str, value: &str| format!("alias {}='{}'\n", name, value.replace('\'', "'\\''"));
+
+    let args = match options("alias", args, "p", "[-p] [name[=value] ...]") {
+        Ok((_, args)) => args,
+        Err(status) => return status,
+    };
+
+    let mut output = String::new();
+    if args.is_empty() {
+        let mut aliases: Vec<_> = shell.aliases.iter().collect();
+        aliases.sort();
+        for (name, value) in aliases {
+            output.push_str(&definition(name, value));
+        }
+        return write_output("alias", &output);
+    }
+
+    let mut status = 0;
+    for arg in args {
+        match arg.split_once('=') {
+            Some((name, value)) => {
+                shell.aliases.insert(name.to_string(), value.to_string());
+            }
+            None => match shell.aliases.get(arg) {
+                Some(value) => output.push_str(&definition(arg, value)),
+                None => {
+                    eprintln!("rush: alias: {}: not found", arg);
+                    status = 1;
+                }
+            },
+        }
+    }
+    status.max(write_output("alias", &output))
+}
+
+fn builtin_unalias(args: &[String], shell: &mut Shell) -> i32 {
+    let synopsis = "[-a] name [name ...]";
+    let (flags, args) = match options("unalias", args, "a", synopsis) {
+        Ok(options) => options,
+        Err(status) => return status,
+    };
+
+    if flags.contains('a') {
+        shell.aliases.clear();
+        return 0;
+    }
+
+    if args.is_empty() {
+        return usage("unalias", "", synopsis);
+    }
+
+    let mut status = 0;
+    for name in args {
+        if shell.aliases.remove(name).is_none() {
+            eprintln!("rush: unalias: {}: not found", name);
+            status = 1;
+        }
+    }
+    status
+}
+
+// Only the `DEBUG` and `ERR` conditions can be trapped. `trap command
+// name...` sets the command, `trap - name...` removes it and `trap` or
+// `trap -p` lists the traps as commands.
+fn builtin_trap(args: &[String], shell: &mut Shell) -> i32 {
+    let synopsis = "[-p] [action condition ...]";
+    let (flags, args) = match options("trap", args, "p", synopsis) {
+        Ok(options) => options,
+        Err(status) => return status,
+    };
+    if args.is_empty() || flags.contains('p') {
+        let mut output = String::new();
+        let signals = signals::signal_names().map(|name| (name, format!("SIG{}", name)));
+        let conditions = ["DEBUG", "ERR"].map(|name| (name, name.to_string()));
+        for (condition, name) in signals.chain(conditions) {
+            if let Some(command) = shell.traps.get(condition) {
+                let command = command.replace('\'', "'\\''");
+                let _ = writeln!(output, "trap -- '{}' {}", command, name);
+            }
+        }
+        return write_output("trap", &output);
+    }
+
+    if args.len() < 2 {
+        return usage("trap", "", synopsis);
+    }
+
+    let mut status = 0;
+    for condition in &args[1..] {
+        if let Some(name) = signals::signal_name(condition) {
+            signals::set_trap(name, &args[0], shell);
+            continue;
+        }
+        if !matches!(condition.as_str(), "DEBUG" | "ERR") {
+            eprintln!("rush: trap: {}: invalid signal specification", condition);
+            status = 1;
+            continue;
+        }
+
+        match args[0].as_str() {
+            "-" => shell.traps.remove(condition),
+            command => shell.traps.insert(condition.clone(), command.to_string()),
+        };
+    }
+    status
+}
+
+fn builtin_wait(args: &[String], shell: &mut Shell) -> i32 {
+    let (flags, args) = match options("wait", args, "n", "[-n] [id ...]") {
+        Ok(options) => options,
+        Err(status) => return status,
+    };
+
+    // `wait -n` returns as soon as any job completes.
+    if flags.contains('n') {
+        return match shell.jobs.wait_any() {
+            Some((_, status)) => status,
+            None => 127,
+        };
+    }
+
+    if args.is_empty() {
+        for id in shell.jobs.ids() {
+            shell.jobs.wait_job(id);
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in args {
+        let id = match arg.strip_prefix('%') {
+            Some(id) => id.parse::<usize>().ok(),
+            None => arg
+                .parse::<pid_t>()
+                .ok()
+                .and_then(|pid| shell.jobs.find_pid(pid)),
+        };
+
+        status = match id.and_then(|id| shell.jobs.wait_job(id)) {
+            Some(status) => status,
+            None => {
+                eprintln!("rush: wait: {}: no such job", arg);
+                127
+            }
+        };
+    }
+
+    status
+}
+
+// The usage and a one-line description of each builtin, for `help`.
+const HELP: [(&str, &str, &str); 27] = [
+    (
+        ".",
+        "filename [arguments]",
+        "Execute commands from a file in the current shell.",
+    ),
+    (":", "[arguments]", "Do nothing, successfully."),
+    (
+        "alias",
+        "[-p] [name[=value] ...]",
+        "Define or display aliases.",
+    ),
+    ("break", "[n]", "Exit from n enclosing loops."),
+    ("cd", "[-L|-P] [dir]", "Change the shell working directory."),
+    (
+        "continue",
+        "[n]",
+        "Resume the next iteration of the nth enclosing loop.",
+    ),
+    (
+        "declare",
+        "[-aiprx] [name[=value] ...]",
+        "Set variable values and attributes.",
+    ),
+    (
+        "echo",
+        "[-neE] [arg ...]",
+        "Write arguments to the standard output.",
+    ),
+    (
+        "exec",
+        "[command [arguments ...]]",
+        "Replace the shell with the given command.",
+    ),
+    ("exit", "[n]", "Exit the shell with status n."),
+    (
+        "export",
+        "[-p] [name[=value] ...]",
+        "Set the export attribute of variables.",
+    ),
+    (
+        "fc",
+        "[-e ename] [-lnr] [first] [last] or fc -s [pat=rep] [command]",
+        "Display or execute commands from the history list.",
+    ),
+    (
+        "help",
+        "[pattern ...]",
+        "Display information about builtin commands.",
+    ),
+    (
+        "local",
+        "[name[=value] ...]",
+        "Define variables local to a function.",
+    ),
+    (
+        "mapfile",
+        "[-t] [-n count] [array]",
+        "Read lines from the standard input into an array.",
+    ),
+    (
+        "pwd",
+        "[-L|-P]",
+        "Print the name of the current working directory.",
+    ),
+    (
+        "read",
+        "[-rs] [-n nchars] [-p prompt] [-t timeout] [name ...]",
+        "Read a line from the standard input and split it into fields.",
+    ),
+    (
+        "readarray",
+        "[-t] [-n count] [array]",
+        "Read lines into an array, like mapfile.",
+    ),
+    (
+        "return",
+        "[n]",
+        "Return from a function or sourced script with status n.",
+    ),
+    (
+        "set",
+        "[-n] [-o option] [--] [arg ...]",
+        "Set shell options and positional parameters.",
+    ),
+    (
+        "shopt",
+        "[-su] [optname ...]",
+        "Set and unset shell options.",
+    ),
+    (
+        "source",
+        "filename [arguments]",
+        "Execute commands from a file in the current shell.",
+    ),
+    (
+        "trap",
+        "[-p] [action condition ...]",
+        "Run commands when the shell receives signals.",
+    ),
+    (
+        "type",
+        "[-t] name [name ...]",
+        "Display how each name would be interpreted as a command.",
+    ),
+    ("unalias", "[-a] name [name ...]", "Remove aliases."),
+    (
+        "unset",
+        "[-f] [-v] [name ...]",
+        "Unset values and attributes of variables and functions.",
+    ),
+    (
+        "wait",
+        "[-n] [id ...]",
+        "Wait for jobs to finish and return their status.",
+    ),
+];
+
+// Without arguments, lists the builtins with a line describing each. With
+// names, which may be patterns, prints the usage of the builtins matching
+// them.
+fn builtin_help(args: &[String], shell: &Shell) -> i32 {
+    let patterns = match options("help", args, "", "[pattern ...]") {
+        Ok((_, patterns)) => patterns,
+        Err(status) => return status,
+    };
+
+    let mut output = String::new();
+    if patterns.is_empty() {
+        for (name, _, description) in HELP {
+            let _ = writeln!(output, "{:<10} {}", name, description);
+        }
+        return write_output("help", &output);
+    }
+
+    let mut status = 0;
+    for pattern in patterns {
+        let pattern: Vec<(char, bool)> = pattern.chars().map(|c| (c, false)).collect();
+        let topics: Vec<_> = HELP
+            .iter()
+            .filter(|(name, ..)| {
+                let name: Vec<char> = name.chars().collect();
+                glob::matches(&pattern, &name, &shell.options)
+            })
+            .collect();
+        if topics.is_empty() {
+            let pattern: String = pattern.iter().map(|(c, _)| c).collect();
+            eprintln!("rush: help: no help topics match `{}'.", pattern);
+            status = 1;
+        }
+        for (name, synopsis, description) in topics {
+            let _ = writeln!(
+                output,
+                "{}: {} {}\n    {}",
+                name, name, synopsis, description
+            );
+        }
+    }
+    match write_output("help", &output) {
+        0 => status,
+        error => error,
+    }
+}
+
+// Describes how each name would be run: `type -t` prints only the kind of
+// command, and names that are not found make it fail.
+fn builtin_type(args: &[String], shell: &mut Shell) -> i32 {
+    let (flags, names) = match options("type", args, "t", "[-t] name [name ...]") {
+        Ok(options) => options,
+        Err(status) => return status,
+    };
+    let short = flags.contains('t');
+
+    let mut output = String::new();
+    let mut status = 0;
+    for name in names {
+        let _ = if let Some(value) = shell.aliases.get(name) {
+            match short {
+                true => writeln!(output, "alias"),
+                false => writeln!(output, "{} is aliased to `{}'", name, value),
+            }
+        } else if KEYWORDS.contains(&name.as_str()) {
+            match short {
+                true => writeln!(output, "keyword"),
+                false => writeln!(output, "{} is a shell keyword", name),
+            }
+        } else if shell.functions.contains_key(name) {
+            match short {
+                true => writeln!(output, "function"),
+                false => writeln!(output, "{} is a function", name),
+            }
+        } else if is_builtin(name) {
+            match short {
+                true => writeln!(output, "builtin"),
+                false => writeln!(output, "{} is a shell builtin", name),
+            }
+        } else if let Some(location) = path(name, shell).filter(|location| can_execute(location)) {
+            match short {
+                true => writeln!(output, "file"),
+                false => writeln!(output, "{} is {}", name, location),
+            }
+        } else {
+            if !short {
+                eprintln!("rush: type: {}: not found", name);
+            }
+            status = 1;
+            continue;
+        };
+    }
+    status.max(write_output("type", &output))
+}
+
+// `unset` removes variables, or functions with `-f`. Without either flag
+// variables are tried first.
+fn builtin_unset(args: &[String], shell: &mut Shell) -> i32 {
+    let (flags, names) = match options("unset", args, "fv", "[-f] [-v] [name ...]") {
+        Ok(options) => options,
+        Err(status) => return status,
+    };
+    if flags.contains('f') && flags.contains('v') {
+        eprintln!("rush: unset: cannot simultaneously unset a function and a variable");
+        return 1;
+    }
+
+    let mut status = 0;
+    for name in names {
+        if flags.contains('f') {
+            shell.functions.remove(name);
+            continue;
+        }
+
+        if !is_name(name) {
+            eprintln!("rush: unset: `{}': not a valid identifier", name);
+            status = 1;
+            continue;
+        }
+
+        if shell.variables.variable(name).is_none() && !flags.contains('v') {
+            shell.functions.remove(name);
+            continue;
+        }
+
+        if let Err(e) = shell.variables.unset(name) {
+            eprintln!("rush: unset: {}", e);
+            status = 1;
+        }
+    }
+    status
+}
+
+// Separates the leading options of a builtin from its operands. Options end
+// at `--` or the first word that doesn't start with `-`, and letters not in
+// `allowed` are usage errors, whose status is returned.
+fn options<'a>(
+    name: &str,
+    args: &'a [String],
+    allowed: &str,
+    synopsis: &str,
+) -> Result<(String, &'a [String]), i32> {
+    let mut flags = String::new();
+    let mut operands = args;
+
+    while let Some(arg) = operands.first() {
+        if arg == "--" {
+            operands = &operands[1..];
+            break;
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            break;
+        }
+
+        for flag in arg.chars().skip(1) {
+            if !allowed.contains(flag) {
+                return Err(usage(name, &format!("-{}", flag), synopsis));
+            }
+            flags.push(flag);
+        }
+        operands = &operands[1..];
+    }
+
+    Ok((flags, operands))
+}
+
+// The arguments of a builtin taking no options, without the `--` that may
+// still end them.
+fn end_of_options(args: &[String]) -> &[String] {
+    match args.first() {
+        Some(arg) if arg == "--" => &args[1..],
+        _ => args,
+    }
+}
+
+// Reports a builtin invoked the wrong way, naming the offending option when
+// there is one, and returns the status of usage errors.
+fn usage(name: &str, option: &str, synopsis: &str) -> i32 {
+    if !option.is_empty() {
+        eprintln!("rush: {}: {}: invalid option", name, option);
+    }
+    eprintln!("rush: {}: usage: {} {}", name, name, synopsis);
+    2
+}
+
+// Writes the output of a builtin, which fails with a message rather than a
+// panic when stdout is gone, like when the reader of a pipe has exited.
+fn write_output(name: &str, output: &str) -> i32 {
+    let mut stdout = std::io::stdout().lock();
+    match stdout
+        .write_all(output.as_bytes())
+        .and_then(|_| stdout.flush())
+    {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("rush: {}: write error: {}", name, error_message(&e));
+            1
+        }
+    }
+}
+
+// The system's description of an error, without Rust's `(os error N)` suffix.
+pub fn error_message(error: &std::io::Error) -> String {
+    match error.raw_os_error() {
+        Some(errno) => unsafe {
+            std::ffi::CStr::from_ptr(libc::strerror(errno))
+                .to_string_lossy()
+                .into_owned()
+        },
+        None => error.to_string(),
+    }
+}
+
+// Resolves a command name through `$PATH`. Names containing a slash are
+// used as they are. Locations are remembered until `$PATH` changes.
+fn path(executable: &str, shell: &mut Shell) -> Option<String> {
+    if executable.contains('/') {
+        return Some(executable.to_string());
+    }
+
+    let path = shell.variables.get("PATH").unwrap_or("");
+    if shell.hash.path != path {
+        shell.hash.path = path.to_string();
+        shell.hash.commands.clear();
+    }
+
+    if let Some(location) = shell.hash.commands.get(executable) {
+        if can_execute(location) {
+            return Some(location.clone());
+        }
+    }
+
+    for path in path.split(':') {
+        let executable_path = path.to_owned() + "/" + executable;
+        if can_execute(&executable_path) {
+            shell
+                .hash
+                .commands
+                .insert(executable.to_string(), executable_path.clone());
+            return Some(executable_path);
+        }
+    }
+
+    None
+}
+
+fn can_execute(path: &str) -> bool {
+    let c_path = c_string(path);
+    unsafe { access(c_path.as_ptr(), X_OK) == 0 }
+}
+
+// Converts text for the C library, where it ends at the first null byte, as
+// it does for the commands bash runs.
+pub fn c_string(text: &str) -> CString {
+    let end = text.find('\0').unwrap_or(text.len());
+    CString::new(&text[..end]).unwrap_or_default()
+}
+
+// Runs `command_not_found_handle` with the command and its arguments when
+// the user defined one, like bash does, or reports the missing command.
+// This happens in the forked child, so the handler is dropped there to
+// keep a missing command inside it from calling it again.
+fn command_not_found(argv: &[String], shell: &mut Shell) -> i32 {
+    match shell.functions.remove("command_not_found_handle") {
+        Some(body) => {
+            let argv: Vec<String> = std::iter::once("command_not_found_handle".to_string())
+                .chain(argv.iter().cloned())
+                .collect();
+            call_function(&body, &argv, shell)
+        }
+        None => {
+            eprintln!("rush: {}: command not found", argv[0]);
+            127
+        }
+    }
+}
+
+fn heredoc_fd(body: &str) -> Result<c_int, String> {
+    let mut template = *b"/tmp/rush-heredoc-XXXXXX\0";
+
+    unsafe {
+        let fd = mkstemp(template.as_mut_ptr() as *mut c_char);
+        if fd < 0 {
+            let error = std::io::Error::last_os_error();
+            return Err(format!(
+                "cannot create temp file for here-document: {}",
+                error_message(&error)
+            ));
+        }
+        unlink(template.as_ptr() as *const c_char);
+
+        let bytes = body.as_bytes();
+        let mut written = 0;
+        while written < bytes.len() {
+            let n = write(
+                fd,
+                bytes[written..].as_ptr() as *const _,
+                bytes.len() - written,
+            );
+            if n <= 0 {
+                close(fd);
+                let error = std::io::Error::last_os_error();
+                return Err(format!(
+                    "cannot write here-document: {}",
+                    error_message(&error)
+                ));
+            }
+            written += n as usize;
+        }
+
+        lseek(fd, 0, SEEK_SET);
+        Ok(fd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::shell::tests::{in_child, run, scratch};
+
+    type Opened = Rc<RefCell<Vec<(String, c_int)>>>;
+
+    // Records the files opened and hands out a pipe whose contents are the
+    // name of the file, followed by a newline.
+    struct RecordingOpener {
+        opened: Opened,
+    }
+
+    impl FdOpener for RecordingOpener {
+        fn open(&self, path: &str, flags: c_int) -> std::io::Result<c_int> {
+            self.opened.borrow_mut().push((path.to_string(), flags));
+
+            let mut fds = [0; 2];
+            if unsafe { pipe2(fds.as_mut_ptr(), O_CLOEXEC) } < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let contents = format!("{}\n", path);
+            unsafe {
+                write(fds[1], contents.as_ptr() as *const _, contents.len());
+                close(fds[1]);
+            }
+            Ok(fds[0])
+        }
+    }
+
+    // Runs `script` in a child with a `RecordingOpener`, returning a line
+    // for each file opened, with its flags, followed by the values of the
+    // variables given.
+    fn record(script: &str, variables: &[&str]) -> String {
+        let (_, output) = in_child(|| {
+            let opened = Rc::new(RefCell::new(Vec::new()));
+            let mut shell = Shell::new();
+            shell.opener = Box::new(RecordingOpener {
+                opened: Rc::clone(&opened),
+            });
+            shell.eval(script);
+
+            let mut output = String::new();
+            for (path, flags) in opened.borrow().iter() {
+                let _ = writeln!(output, "{} {}", path, flags);
+            }
+            for name in variables {
+                let _ = writeln!(output, "{}", shell.variables.get(name).unwrap_or(""));
+            }
+            write_output("test", &output)
+        });
+        output
+    }
+
+    #[test]
+    fn redirect_flags() {
+        let expected = [
+            format!("in {}", O_RDONLY),
+            format!("out {}", O_WRONLY | O_CREAT | O_TRUNC),
+            format!("log {}", O_WRONLY | O_CREAT | O_APPEND),
+            format!("err {}", O_WRONLY | O_CREAT | O_TRUNC),
+            "in".to_string(),
+        ];
+        let output = record("read x < in; : > out >> log 2> err", &["x"]);
+        assert_eq!(output.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn redirect_order() {
+        // Redirections apply from left to right, so the last one to a
+        // descriptor wins, and `<&4` sees the file opened on 4 before it.
+        let output = record("read x < first < second; read y 4< third <&4", &["x", "y"]);
+        let paths: Vec<&str> = output
+            .lines()
+            .map(|line| line.split(' ').next().unwrap())
+            .collect();
+        assert_eq!(paths, ["first", "second", "third", "second", "third"]);
+    }
+
+    #[test]
+    fn heredocs_bypass_the_opener() {
+        let output = record("read x <<EOF\nbody\nEOF\n", &["x"]);
+        assert_eq!(output, "body\n");
+    }
+
+    #[test]
+    fn noexec_skips_later_commands() {
+        assert_eq!(
+            run("echo a; set -n; echo b\necho c"),
+            (0, "a\n".to_string())
+        );
+    }
+
+    #[test]
+    fn exit_in_subshell_and_group() {
+        assert_eq!(run("(exit 3); echo $?"), (0, "3\n".to_string()));
+        assert_eq!(run("{ (exit 3); }; echo $?"), (0, "3\n".to_string()));
+        assert_eq!(run("{ exit 3; }; echo $?"), (3, String::new()));
+    }
+
+    #[test]
+    fn exec_keeps_fd_open_across_commands() {
+        let dir = scratch("exec-fd", &[]);
+        std::fs::write(dir.join("input"), "l1\nl2\nl3\n").unwrap();
+        let script = format!(
+            "exec 3< {}/input; read a <&3; read b <&3; echo $a $b; cat <&3; exec 3<&-; read c <&3; echo $?",
+            dir.display()
+        );
+        assert_eq!(run(&script), (0, "l1 l2\nl3\n1\n".to_string()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn pipeline_status_with_pipefail() {
+        let script = "false | true; echo $?; true | false; echo $?";
+        assert_eq!(run(script), (0, "0\n1\n".to_string()));
+        let script = "set -o pipefail; false | true; echo $?; (exit 2) | (exit 3) | true; echo $?";
+        assert_eq!(run(script), (0, "1\n3\n".to_string()));
+    }
+
+    #[test]
+    fn builtin_usage_errors() {
+        for command in [
+            "cd -x",
+            "export -z",
+            "unset -q x",
+            "type -z ls",
+            "read -z",
+            "wait -z",
+            "shopt -z",
+            "trap -z",
+            "help -z",
+            "set -Z",
+        ] {
+            let script = format!("{}; echo $?", command);
+            assert_eq!(run(&script), (0, "2\n".to_string()), "{}", command);
+        }
+        assert_eq!(run("exit a"), (2, String::new()));
+
+        // Options a builtin doesn't take are plain arguments.
+        assert_eq!(run("echo -z; echo $?"), (0, "-z\n0\n".to_string()));
+    }
+
+    #[test]
+    fn redirections_before_the_command() {
+        let dir = scratch("leading-redirect", &[]);
+        std::fs::write(dir.join("old"), "old\n").unwrap();
+        let script = format!(
+            "cd {}; > out echo hi; 2> err >> out echo there; > old",
+            dir.display()
+        );
+        assert_eq!(run(&script), (0, String::new()));
+        let out = std::fs::read_to_string(dir.join("out")).unwrap();
+        assert_eq!(out, "hi\nthere\n");
+        assert_eq!(std::fs::read_to_string(dir.join("old")).unwrap(), "");
+        assert_eq!(std::fs::read_to_string(dir.join("err")).unwrap(), "");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn redirect_into_missing_directory() {
+        let script = "echo hi > /nonexistent/dir/f; echo $?; > /nonexistent/f; echo $?";
+        assert_eq!(run(script), (0, "1\n1\n".to_string()));
+        assert_eq!(run("{ echo hi; } > /nonexistent/f"), (1, String::new()));
+    }
+
+    #[test]
+    fn cd_reports_and_resolves() {
+        let path = scratch("cd", &[]).canonicalize().unwrap();
+        std::fs::create_dir_all(path.join("real")).unwrap();
+        std::fs::create_dir_all(path.join("cdpath/target")).unwrap();
+        std::os::unix::fs::symlink(path.join("real"), path.join("link")).unwrap();
+        let dir = path.display();
+
+        // Only `cd -` and a directory found through CDPATH are printed.
+        let script = format!("cd {dir}; cd link; pwd; cd ..; cd -P link; pwd; echo $PWD $OLDPWD");
+        let expected = format!("{dir}/link\n{dir}/real\n{dir}/real {dir}\n");
+        assert_eq!(run(&script), (0, expected));
+        let script = format!("cd /; CDPATH={dir}/cdpath cd target; cd -; echo $PWD $OLDPWD");
+        let expected = format!("{dir}/cdpath/target\n/\n/ {dir}/cdpath/target\n");
+        assert_eq!(run(&script), (0, expected));
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn mapfile_reads_lines() {
+        // The last stage of a pipeline runs in a subshell, so the array is
+        // used in the same group.
+        let script = "printf 'a\\nb\\nc\\n' | { mapfile -t arr; echo ${arr[1]} ${#arr[@]}; }";
+        assert_eq!(run(script), (0, "b 3\n".to_string()));
+        let script = "readarray -n 2 < <(printf 'a\\nb\\nc\\n'); printf '[%s]' \"${MAPFILE[@]}\"";
+        assert_eq!(run(script), (0, "[a\n][b\n]".to_string()));
+    }
+
+    #[test]
+    fn select_reads_choices() {
+        let script = "printf '2\\n\\n9\\n1\\n' | { select v in a b c; do echo \"$REPLY:$v\"; done; echo $?; }";
+        assert_eq!(run(script), (0, "2:b\n9:\n1:a\n0\n".to_string()));
+        let script =
+            "printf '3\\n1\\n' | { select v in a b c; do echo $v; break; done; echo $REPLY; }";
+        assert_eq!(run(script), (0, "c\n3\n".to_string()));
+    }
+
+    #[test]
+    fn redirections_on_compound_commands() {
+        let path = scratch("compound-redirect", &[]);
+        std::fs::write(path.join("in"), "a\nb\n").unwrap();
+        let script = format!(
+            "cd {}; while read l; do echo \"<$l>\"; done < in; {{ echo a; echo b; }} > out; \
+             if true; then echo c; fi >> out; for x in 1 2; do echo $x; done > loop; echo d",
+            path.display()
+        );
+        assert_eq!(run(&script), (0, "<a>\n<b>\nd\n".to_string()));
+        assert_eq!(
+            std::fs::read_to_string(path.join("out")).unwrap(),
+            "a\nb\nc\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(path.join("loop")).unwrap(),
+            "1\n2\n"
+        );
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn end_of_options_marker() {
+        let path = scratch("end-of-options", &[]).canonicalize().unwrap();
+        std::fs::create_dir(path.join("-weird")).unwrap();
+        let script = format!("cd {}; cd -- -weird; pwd", path.display());
+        assert_eq!(run(&script), (0, format!("{}/-weird\n", path.display())));
+
+        let script = "x=1; unset -- x; echo ${x-unset}; export -- y=2; echo $y; echo -- -n";
+        assert_eq!(run(script), (0, "unset\n2\n-- -n\n".to_string()));
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn read_timeout_and_count() {
+        // The input stays open but empty until `sleep` ends.
+        let script = "sleep 0.5 | { read -t 0.1 x; echo $? \"[$x]\"; }; read -t 0.1 x; echo $?";
+        assert_eq!(run(script), (0, "142 []\n1\n".to_string()));
+
+        let script = "printf abcdef | { read -n 3 x; echo $? $x; read y; echo $? $y; }";
+        assert_eq!(run(script), (0, "0 abc\n1 def\n".to_string()));
+    }
+
+    #[test]
+    fn help_topics() {
+        let (status, output) = run("help");
+        assert_eq!(status, 0);
+        for name in ["cd", "echo", "export", "help", "read"] {
+            let entry = format!("\n{:<10} ", name);
+            assert!(
+                output.contains(&entry),
+                "{} missing from {:?}",
+                name,
+                output
+            );
+        }
+
+        let (status, output) = run("help cd");
+        assert_eq!(status, 0);
+        assert!(output.starts_with("cd: cd [-L|-P] [dir]\n"), "{:?}", output);
+        assert_eq!(run("help nosuch"), (1, String::new()));
+    }
+
+    #[test]
+    fn heredocs_on_different_fds() {
+        let script = "{ cat; cat <&3; } <<A 3<<B\none\nA\ntwo\nB\n";
+        assert_eq!(run(script), (0, "one\ntwo\n".to_string()));
+        let script = "cat /dev/fd/3 - 3<<B <<A\ntwo\nB\none\nA\n";
+        assert_eq!(run(script), (0, "two\none\n".to_string()));
+    }
+
+    #[test]
+    fn heredoc_quoting_disables_expansion() {
+        let body = "\t$x \\$x $(echo s)\n\tEOF\n";
+        for (redirect, output) in [
+            ("<<EOF", "\t1 $x s\n"),
+            ("<<'EOF'", "\t$x \\$x $(echo s)\n"),
+            ("<<-EOF", "1 $x s\n"),
+            ("<<-\"EOF\"", "$x \\$x $(echo s)\n"),
+        ] {
+            let body = match redirect.starts_with("<<-") {
+                true => body.to_string(),
+                false => body.replace("\tEOF", "EOF"),
+            };
+            let script = format!("x=1; cat {}\n{}", redirect, body);
+            assert_eq!(run(&script), (0, output.to_string()), "{}", redirect);
+        }
+    }
+
+    #[test]
+    fn echo_keeps_quoted_spacing() {
+        assert_eq!(run("echo \"a  b\" c"), (0, "a  b c\n".to_string()));
+        assert_eq!(run("echo 'a  b'   c\"  \""), (0, "a  b c  \n".to_string()));
+        let (_, output) = run("x='a  b'; echo $x \"$x\"");
+        assert_eq!(output, "a b a  b\n");
+    }
+
+    #[test]
+    fn case_ignores_nocaseglob() {
+        let script = "shopt -s nocaseglob; case Foo in foo) echo lower;; Foo) echo same;; esac";
+        assert_eq!(run(script), (0, "same\n".to_string()));
+    }
+
+    #[test]
+    fn assignments_without_command() {
+        assert_eq!(run("A=1 B=2; echo $A $B"), (0, "1 2\n".to_string()));
+        assert_eq!(run("A=1 B=$A; echo $B"), (0, "1\n".to_string()));
+
+        // `export NAME` only marks the name, the assignment sets it.
+        let script = "unset FOO; export FOO; env | grep ^FOO; FOO=bar; env | grep ^FOO";
+        assert_eq!(run(script), (0, "FOO=bar\n".to_string()));
+    }
+
+    #[test]
+    fn echo_escapes_follow_xpg_echo() {
+        assert_eq!(run(r"echo 'a\nb'"), (0, "a\\nb\n".to_string()));
+        assert_eq!(run(r"echo -e 'a\nb\c'; echo c"), (0, "a\nbc\n".to_string()));
+        let script = r"shopt -s xpg_echo; echo 'a\nb'; echo -E 'a\nb'";
+        assert_eq!(run(script), (0, "a\nb\na\\nb\n".to_string()));
+    }
+
+    #[test]
+    fn case_terminators_on_overlapping_patterns() {
+        // Each pattern below matches `ab`, except `x*`.
+        let case = |terminator: &str| {
+            format!(
+                "case ab in a*) echo 1 {t} x*) echo 2 {t} *b) echo 3 {t} *) echo 4;; esac",
+                t = terminator
+            )
+        };
+        assert_eq!(run(&case(";;")), (0, "1\n".to_string()));
+        assert_eq!(run(&case(";&")), (0, "1\n2\n3\n4\n".to_string()));
+        assert_eq!(run(&case(";;&")), (0, "1\n3\n4\n".to_string()));
+    }
+
+    #[test]
+    fn wait_n_returns_after_the_first_job() {
+        // The long job is killed so it doesn't hold the output pipe open.
+        let start = Instant::now();
+        let script = "sleep 2 > /dev/null & p=$!; (sleep 0.2; exit 3) & wait -n; echo $?; kill $p";
+        assert_eq!(run(script), (0, "3\n".to_string()));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        assert_eq!(run("wait -n; echo $?"), (0, "127\n".to_string()));
+    }
+
+    #[test]
+    fn wait_n_leaves_other_children() {
+        // The child of the process substitution exits first, but it isn't
+        // a job, so `wait -n` goes on to wait for the one that is.
+        let script = "(sleep 0.2; exit 4) & wait -n <(true); echo $?";
+        assert_eq!(run(script), (0, "4\n".to_string()));
     }
 }
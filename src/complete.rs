@@ -0,0 +1,189 @@
+use std::ffi::{CStr, CString};
+use std::sync::Mutex;
+
+use libc::{c_char, c_int, malloc, strdup};
+
+use crate::expand::is_assignment;
+use crate::shell::Shell;
+
+extern "C" {
+    static mut rl_attempted_completion_function:
+        Option<extern "C" fn(*const c_char, c_int, c_int) -> *mut *mut c_char>;
+    static mut rl_attempted_completion_over: c_int;
+    static mut rl_filename_completion_desired: c_int;
+    static rl_line_buffer: *mut c_char;
+}
+
+// What completion knows of the shell. Readline calls back without any way
+// to reach the shell, so this is refreshed before each prompt.
+struct Context {
+    variables: Vec<String>,
+    aliases: Vec<String>,
+    jobs: Vec<String>, // Job specifications like `%1`
+}
+
+static CONTEXT: Mutex<Context> = Mutex::new(Context {
+    variables: Vec::new(),
+    aliases: Vec::new(),
+    jobs: Vec::new(),
+});
+
+// Words that may precede a command without being one.
+const PREFIXES: [&str; 9] = [
+    "!", "do", "elif", "else", "if", "then", "time", "until", "while",
+];
+
+pub fn init() {
+    unsafe { rl_attempted_completion_function = Some(attempted_completion) };
+}
+
+pub fn update(shell: &Shell) {
+    let mut aliases: Vec<String> = shell.aliases.keys().cloned().collect();
+    aliases.sort();
+
+    let context = Context {
+        variables: shell
+            .variables
+            .list()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect(),
+        aliases,
+        jobs: shell
+            .jobs
+            .ids()
+            .iter()
+            .map(|id| format!("%{}", id))
+            .collect(),
+    };
+    if let Ok(mut current) = CONTEXT.lock() {
+        *current = context;
+    }
+}
+
+// Completes the arguments of the commands that take something other than
+// file names. Everything else is left to readline, which completes file
+// names.
+extern "C" fn attempted_completion(
+    text: *const c_char,
+    start: c_int,
+    _end: c_int,
+) -> *mut *mut c_char {
+    unsafe {
+        let line = CStr::from_ptr(rl_line_buffer).to_string_lossy();
+        let Some(command) = line.get(..start as usize).and_then(command_word) else {
+            return std::ptr::null_mut();
+        };
+        let text = CStr::from_ptr(text).to_string_lossy();
+
+        let candidates = match CONTEXT.lock() {
+            Ok(context) => candidates(&command, &text, &context),
+            Err(_) => None,
+        };
+        match candidates {
+            Some(candidates) => {
+                rl_attempted_completion_over = 1;
+                rl_filename_completion_desired = (command == "cd") as c_int;
+                matches_array(candidates)
+            }
+            None => std::ptr::null_mut(),
+        }
+    }
+}
+
+// The command whose arguments are being typed, given the line up to the
+// word being completed, or `None` when that word is the command itself.
+fn command_word(before: &str) -> Option<String> {
+    let start = before
+        .rfind([';', '|', '&', '(', '{', '\n'])
+        .map_or(0, |i| i + 1);
+    before[start..]
+        .split_whitespace()
+        .find(|word| !is_assignment(word) && !PREFIXES.contains(word))
+        .map(String::from)
+}
+
+// The candidates for `text` as an argument of `command`: directories for
+// `cd`, variable names for the builtins acting on variables, aliases for
+// `unalias` and job specifications for `wait`. `None` for other commands.
+fn candidates(command: &str, text: &str, context: &Context) -> Option<Vec<String>> {
+    let matching = |names: &[String]| -> Vec<String> {
+        names
+            .iter()
+            .filter(|name| name.starts_with(text))
+            .cloned()
+            .collect()
+    };
+
+    match command {
+        "cd" => Some(directories(text)),
+        "declare" | "export" | "local" | "read" | "unset" => Some(matching(&context.variables)),
+        "alias" | "unalias" => Some(matching(&context.aliases)),
+        "wait" => Some(matching(&context.jobs)),
+        _ => None,
+    }
+}
+
+// The directories whose path starts with `text`. Hidden ones are only
+// offered when the name being completed starts with a dot.
+fn directories(text: &str) -> Vec<String> {
+    let (directory, prefix) = match text.rfind('/') {
+        Some(i) => (&text[..i + 1], &text[i + 1..]),
+        None => ("", text),
+    };
+
+    let Ok(entries) = std::fs::read_dir(if directory.is_empty() { "." } else { directory }) else {
+        return vec![];
+    };
+
+    entries
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| {
+            name.starts_with(prefix) && (prefix.starts_with('.') || !name.starts_with('.'))
+        })
+        .map(|name| format!("{}{}", directory, name))
+        .collect()
+}
+
+// Builds the array readline expects from a completion function, allocated
+// with `malloc` since readline frees it: the text to substitute, which is
+// the longest prefix common to all candidates, then the candidates when
+// there are several, and a null pointer.
+fn matches_array(mut candidates: Vec<String>) -> *mut *mut c_char {
+    candidates.sort();
+    candidates.dedup();
+    if candidates.is_empty() {
+        return std::ptr::null_mut();
+    }
+
+    let mut common = candidates[0].clone();
+    for candidate in &candidates[1..] {
+        let length = common
+            .char_indices()
+            .zip(candidate.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(common.len().min(candidate.len()), |((i, _), _)| i);
+        common.truncate(length);
+    }
+
+    let mut items = vec![common];
+    if candidates.len() > 1 {
+        items.extend(candidates);
+    }
+
+    unsafe {
+        let size = (items.len() + 1) * std::mem::size_of::<*mut c_char>();
+        let array = malloc(size) as *mut *mut c_char;
+        if array.is_null() {
+            return array;
+        }
+        for (i, item) in items.iter().enumerate() {
+            let item = CString::new(item.as_str()).unwrap_or_default();
+            *array.add(i) = strdup(item.as_ptr());
+        }
+        *array.add(items.len()) = std::ptr::null_mut();
+        array
+    }
+}
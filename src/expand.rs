@@ -0,0 +1,1482 @@
+use std::ffi::{CStr, CString};
+use std::io::Write;
+use std::ops::Range;
+
+use libc::{
+    c_int, close, dup2, exit, fork, getpwnam, getpwuid, getuid, pipe, pipe2, read, O_CLOEXEC,
+};
+
+use crate::arithmetic;
+use crate::command::{error_message, RedirectOperator};
+use crate::glob;
+use crate::jobs::{exit_status, waitpid};
+use crate::lexer::{Lexer, Token};
+use crate::shell::Shell;
+
+// Whether the word assigns a variable, `NAME=value`, or an element of an
+// array, `NAME[index]=value`, or appends to one with `+=`.
+pub fn is_assignment(word: &str) -> bool {
+    split_assignment(word).is_some()
+}
+
+// Splits an assignment word into its target, whether it appends with `+=`,
+// and its value.
+pub fn split_assignment(word: &str) -> Option<(&str, bool, &str)> {
+    let (target, value) = word.split_once('=')?;
+    let (target, append) = match target.strip_suffix('+') {
+        Some(target) => (target, true),
+        None => (target, false),
+    };
+
+    if is_name(target) || subscript(target).is_some() {
+        Some((target, append, value))
+    } else {
+        None
+    }
+}
+
+// Splits `name[subscript]` into the name and the subscript.
+pub fn subscript(name: &str) -> Option<(&str, &str)> {
+    let (base, rest) = name.split_once('[')?;
+    let subscript = rest.strip_suffix(']')?;
+    if is_name(base) {
+        Some((base, subscript))
+    } else {
+        None
+    }
+}
+
+// Splits an array assignment, `NAME=(a b c)` or `NAME+=(a b c)`, into the
+// name, whether it appends, and the text of the elements.
+pub fn array_literal(word: &str) -> Option<(&str, bool, &str)> {
+    let (name, append, value) = split_assignment(word)?;
+    let elements = value.strip_prefix('(')?.strip_suffix(')')?;
+    if is_name(name) {
+        Some((name, append, elements))
+    } else {
+        None
+    }
+}
+
+// Expands the elements of an array literal like the arguments of a command.
+pub fn expand_array(elements: &str, shell: &mut Shell) -> Result<Vec<String>, String> {
+    let mut values = vec![];
+    for token in Lexer::new(elements.to_string()).tokens() {
+        match token {
+            Token::Word(word) => values.extend(expand_word(&word, shell)?),
+            Token::Newline => {}
+            token => return Err(format!("syntax error near unexpected token '{}'", token)),
+        }
+    }
+
+    Ok(values)
+}
+
+pub fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+// Expands a word into fields, failing only when `failglob` is set and a
+// pattern matches nothing. Quotes are never part of the expanded text, only
+// recorded on each character, so quote removal effectively comes last: a
+// quote produced by an expansion is an ordinary character, and quoted ones
+// are neither split nor globbed.
+pub fn expand_word(word: &str, shell: &mut Shell) -> Result<Vec<String>, String> {
+    let (chars, quoted) = expand(word, false, shell);
+    let ifs = ifs(shell);
+
+    // The parameters of `$@` are separated by boundaries that always end a
+    // field. Fields next to a boundary from `"$@"` are kept even when empty.
+    let mut words = vec![];
+    let mut keep = false;
+    for segment in chars.split_inclusive(|e| e.boundary) {
+        let (segment, boundary) = match segment.split_last() {
+            Some((last, rest)) if last.boundary => (rest, Some(last.quoted)),
+            _ => (segment, None),
+        };
+
+        let keep_segment = keep || boundary == Some(true);
+        keep = boundary == Some(true);
+        glob_fields(word, segment, keep_segment, &ifs, shell, &mut words)?;
+    }
+
+    if words.is_empty() && quoted {
+        return Ok(vec![String::new()]);
+    }
+
+    Ok(words)
+}
+
+// Splits expanded characters into fields and expands the fields that are
+// patterns into the paths they match.
+fn glob_fields(
+    word: &str,
+    chars: &[Expanded],
+    keep: bool,
+    ifs: &str,
+    shell: &Shell,
+    words: &mut Vec<String>,
+) -> Result<(), String> {
+    let text: Vec<char> = chars.iter().map(|e| e.c).collect();
+
+    let fields = split_fields(&text, |i| chars[i].split, ifs, usize::MAX);
+    if fields.is_empty() && keep {
+        words.push(String::new());
+    }
+
+    for range in fields {
+        let pattern: Vec<(char, bool)> = chars[range.clone()]
+            .iter()
+            .map(|e| (e.c, e.quoted))
+            .collect();
+
+        if !glob::is_pattern(&pattern, &shell.options) {
+            words.push(text[range].iter().collect());
+            continue;
+        }
+
+        let matches = glob::glob(&pattern, &shell.options);
+        if !matches.is_empty() {
+            words.extend(matches);
+        } else if shell.options.enabled("failglob") {
+            return Err(format!("no match: {}", word));
+        } else if !shell.options.enabled("nullglob") {
+            words.push(text[range].iter().collect());
+        }
+    }
+
+    Ok(())
+}
+
+// Expands a `NAME=value` word, where a tilde is also recognized right after
+// the `=` and after every unquoted `:` of the value. The result is never
+// split into fields.
+pub fn expand_assignment(word: &str, shell: &mut Shell) -> String {
+    let (chars, _) = expand(word, true, shell);
+    chars.iter().map(|e| e.c).collect()
+}
+
+// Expands a word that is neither split into fields nor globbed, like the
+// word `case` matches against its patterns.
+pub fn expand_string(word: &str, shell: &mut Shell) -> String {
+    let (chars, _) = expand(word, false, shell);
+    chars.iter().map(|e| e.c).collect()
+}
+
+// Expands a pattern of `case`, keeping which characters were quoted so that
+// those only match themselves.
+pub fn expand_pattern(word: &str, shell: &mut Shell) -> Vec<(char, bool)> {
+    let (chars, _) = expand(word, false, shell);
+    chars.iter().map(|e| (e.c, e.quoted)).collect()
+}
+
+pub fn ifs(shell: &Shell) -> String {
+    shell.variables.get("IFS").unwrap_or(" \t\n").to_string()
+}
+
+// Splits `value` into fields on the characters of `ifs`. At most `limit`
+// fields are produced, the last one holding the rest of the value.
+pub fn split_ifs(value: &str, ifs: &str, limit: usize) -> Vec<String> {
+    let chars: Vec<char> = value.chars().collect();
+
+    split_fields(&chars, |_| true, ifs, limit)
+        .into_iter()
+        .map(|range| chars[range].iter().collect())
+        .collect()
+}
+
+// Field splitting shared by expansion and `read`: `splittable` tells whether
+// the character at an index may act as a delimiter. Runs of IFS whitespace
+// count as one delimiter and are trimmed at both ends, while every other IFS
+// character delimits a field on its own, so empty fields are preserved.
+// Only space, tab and newline count as IFS whitespace, as in POSIX.
+pub(crate) fn split_fields(
+    chars: &[char],
+    splittable: impl Fn(usize) -> bool,
+    ifs: &str,
+    limit: usize,
+) -> Vec<Range<usize>> {
+    let is_delimiter = |i: usize| splittable(i) && ifs.contains(chars[i]);
+    let is_space = |i: usize| is_delimiter(i) && matches!(chars[i], ' ' | '\t' | '\n');
+
+    let mut fields = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() && is_space(i) {
+        i += 1;
+    }
+
+    while i < chars.len() {
+        if fields.len() + 1 == limit {
+            let mut end = chars.len();
+            while end > i && is_space(end - 1) {
+                end -= 1;
+            }
+            fields.push(i..end);
+            break;
+        }
+
+        let start = i;
+        while i < chars.len() && !is_delimiter(i) {
+            i += 1;
+        }
+        fields.push(start..i);
+
+        while i < chars.len() && is_space(i) {
+            i += 1;
+        }
+        if i < chars.len() && is_delimiter(i) {
+            i += 1;
+            while i < chars.len() && is_space(i) {
+                i += 1;
+            }
+        }
+    }
+
+    fields
+}
+
+// A character left after quote removal, remembering whether it was quoted
+// and whether it came from an unquoted expansion, which makes it subject to
+// field splitting. Boundaries stand between the parameters of `$@` and
+// carry no character of their own.
+#[derive(Clone, Copy)]
+struct Expanded {
+    c: char,
+    quoted: bool,
+    split: bool,
+    boundary: bool,
+}
+
+// Performs tilde, parameter and command expansion along with quote removal.
+// The flag returned alongside tells whether the word contained any quoting.
+fn expand(word: &str, assignment: bool, shell: &mut Shell) -> (Vec<Expanded>, bool) {
+    let chars: Vec<char> = word.chars().collect();
+    let mut result: Vec<Expanded> = Vec::new();
+    let mut quoted = false;
+    let mut i = 0;
+
+    let value_start = if assignment {
+        chars.iter().position(|&c| c == '=').map(|index| index + 1)
+    } else {
+        None
+    };
+
+    let push = |result: &mut Vec<Expanded>, value: &str, quoted: bool, split: bool| {
+        result.extend(value.chars().map(|c| Expanded {
+            c,
+            quoted,
+            split: split && !assignment,
+            boundary: false,
+        }));
+    };
+
+    // Pushes each positional parameter as if expanded on its own, with
+    // boundaries in between, or spaces when the word is never split.
+    let push_parameters = |result: &mut Vec<Expanded>, parameters: &[String], quoted: bool| {
+        for (n, parameter) in parameters.iter().enumerate() {
+            if n > 0 && assignment {
+                push(result, " ", quoted, false);
+            } else if n > 0 {
+                result.push(Expanded {
+                    c: ' ',
+                    quoted,
+                    split: false,
+                    boundary: true,
+                });
+            }
+            push(result, parameter, quoted, !quoted);
+        }
+    };
+
+    while i < chars.len() {
+        let tilde_position = match value_start {
+            Some(start) => i == start || (i > start && chars[i - 1] == ':'),
+            None => i == 0,
+        };
+
+        if tilde_position && chars[i] == '~' {
+            if let Some((home, consumed)) = expand_tilde(&chars[i..], assignment, shell) {
+                push(&mut result, &home, true, false);
+                i += consumed;
+                continue;
+            }
+        }
+
+        match chars[i] {
+            '\'' => {
+                quoted = true;
+                let end = find_closing(&chars, i + 1, '\'').unwrap_or(chars.len());
+                let value: String = chars[i + 1..end].iter().collect();
+                push(&mut result, &value, true, false);
+                i = end + 1;
+            }
+            '"' if is_empty_quoted_list(&chars[i..], shell) => {
+                // `"$@"` without parameters expands to no field at all.
+                i = find_closing(&chars, i + 1, '"').unwrap() + 1;
+            }
+            '"' => {
+                quoted = true;
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    match chars[i] {
+                        '\\' if i + 1 < chars.len() => match chars[i + 1] {
+                            '$' | '`' | '"' | '\\' => {
+                                push(&mut result, &chars[i + 1].to_string(), true, false);
+                                i += 2;
+                            }
+                            '\n' => i += 2,
+                            _ => {
+                                push(&mut result, "\\", true, false);
+                                i += 1;
+                            }
+                        },
+                        '$' if matches!(parameter_list(&chars[i..], shell), Some(('@', ..))) => {
+                            let (_, consumed, values) = parameter_list(&chars[i..], shell).unwrap();
+                            push_parameters(&mut result, &values, true);
+                            i += consumed;
+                        }
+                        '$' => {
+                            let (value, consumed) = expand_dollar(&chars[i..], shell);
+                            push(&mut result, &value, true, false);
+                            i += consumed;
+                        }
+                        '`' => {
+                            let mut value = String::new();
+                            i += expand_backquote(&chars[i..], &mut value, shell);
+                            push(&mut result, &value, true, false);
+                        }
+                        c => {
+                            push(&mut result, &c.to_string(), true, false);
+                            i += 1;
+                        }
+                    }
+                }
+                i += 1;
+            }
+            '\\' => {
+                quoted = true;
+                if let Some(&c) = chars.get(i + 1) {
+                    if c != '\n' {
+                        push(&mut result, &c.to_string(), true, false);
+                    }
+                }
+                i += 2;
+            }
+            '<' | '>' if chars.get(i + 1) == Some(&'(') => {
+                let end = find_matching(&chars, i + 2, ')').unwrap_or(chars.len());
+                let command: String = chars[i + 2..end].iter().collect();
+                let path = process_substitution(&command, chars[i] == '<', shell);
+                push(&mut result, &path, true, false);
+                i = end + 1;
+            }
+            '$' if chars.get(i + 1) == Some(&'\'') => {
+                quoted = true;
+                let mut end = i + 2;
+                while end < chars.len() && chars[end] != '\'' {
+                    end += if chars[end] == '\\' { 2 } else { 1 };
+                }
+                let end = end.min(chars.len());
+                let value: String = chars[i + 2..end].iter().collect();
+                push(&mut result, &ansi_c(&value), true, false);
+                i = end + 1;
+            }
+            '$' if parameter_list(&chars[i..], shell).is_some() => {
+                let (_, consumed, values) = parameter_list(&chars[i..], shell).unwrap();
+                push_parameters(&mut result, &values, false);
+                i += consumed;
+            }
+            '$' => {
+                let (value, consumed) = expand_dollar(&chars[i..], shell);
+                push(&mut result, &value, false, true);
+                i += consumed;
+            }
+            '`' => {
+                let mut value = String::new();
+                i += expand_backquote(&chars[i..], &mut value, shell);
+                push(&mut result, &value, false, true);
+            }
+            c => {
+                push(&mut result, &c.to_string(), false, false);
+                i += 1;
+            }
+        }
+    }
+
+    (result, quoted)
+}
+
+// Recognizes an expansion of a whole list at the start of `chars`: `$@`,
+// `$*`, `${@}`, `${*}`, `${name[@]}` or `${name[*]}`. Returns whether it
+// uses `@` or `*`, its length and the values of the list.
+fn parameter_list(chars: &[char], shell: &Shell) -> Option<(char, usize, Vec<String>)> {
+    match chars {
+        ['$', c @ ('@' | '*'), ..] => Some((*c, 2, shell.positional.clone())),
+        ['$', '{', ..] => {
+            let end = find_closing(chars, 2, '}')?;
+            let name: String = chars[2..end].iter().collect();
+            let (kind, values) = match subscript(&name) {
+                Some((base, kind @ ("@" | "*"))) => (kind, elements(base, shell)),
+                _ if name == "@" || name == "*" => (name.as_str(), shell.positional.clone()),
+                _ => match prefix_pattern(&name) {
+                    Some((prefix, kind)) => (kind, prefixed_names(prefix, shell)),
+                    None => return None,
+                },
+            };
+            Some((kind.chars().next().unwrap(), end + 1, values))
+        }
+        _ => None,
+    }
+}
+
+// Whether `chars` starts with a double-quoted `$@` or `${name[@]}`, and
+// nothing else, that expands to an empty list.
+fn is_empty_quoted_list(chars: &[char], shell: &Shell) -> bool {
+    match parameter_list(&chars[1..], shell) {
+        Some(('@', len, values)) => values.is_empty() && chars.get(1 + len) == Some(&'"'),
+        _ => false,
+    }
+}
+
+// Splits `!prefix@` or `!prefix*` into the prefix and the list character.
+fn prefix_pattern(name: &str) -> Option<(&str, &str)> {
+    let pattern = name.strip_prefix('!')?;
+    let (prefix, kind) = pattern.split_at(pattern.len().checked_sub(1)?);
+    (matches!(kind, "@" | "*") && is_name(prefix)).then_some((prefix, kind))
+}
+
+// The names of the variables starting with `prefix`, sorted.
+fn prefixed_names(prefix: &str, shell: &Shell) -> Vec<String> {
+    shell
+        .variables
+        .list()
+        .into_iter()
+        .filter(|(name, variable)| variable.is_set() && name.starts_with(prefix))
+        .map(|(name, _)| name)
+        .collect()
+}
+
+fn elements(name: &str, shell: &Shell) -> Vec<String> {
+    shell
+        .variables
+        .variable(name)
+        .map(|variable| variable.elements())
+        .unwrap_or_default()
+}
+
+// Expands a `~` or `~user` prefix, returning `None` when the prefix contains
+// quoted characters or names an unknown user.
+fn expand_tilde(chars: &[char], assignment: bool, shell: &mut Shell) -> Option<(String, usize)> {
+    let len = chars[1..]
+        .iter()
+        .take_while(|&&c| c != '/' && !(assignment && c == ':'))
+        .count();
+    let user: String = chars[1..1 + len].iter().collect();
+
+    if user.contains(['\'', '"', '\\', '$', '`']) {
+        return None;
+    }
+
+    // `~+` and `~-` stand for the current and previous directories, and are
+    // left alone when those are unset.
+    let home = if user.is_empty() {
+        match shell.variables.get("HOME") {
+            Some(home) => Some(home.to_string()),
+            None => unsafe { passwd_home(getpwuid(getuid())) },
+        }
+    } else if user == "+" {
+        shell.variables.get("PWD").map(String::from)
+    } else if user == "-" {
+        shell.variables.get("OLDPWD").map(String::from)
+    } else {
+        user_home(&user, shell)
+    };
+
+    home.map(|home| (home, 1 + len))
+}
+
+// Looks up the home directory of a user, remembering it for the life of
+// the shell since the lookup may go through the network. Unknown users are
+// looked up again each time, as they may be added meanwhile.
+fn user_home(user: &str, shell: &mut Shell) -> Option<String> {
+    if let Some(home) = shell.homes.get(user) {
+        return Some(home.clone());
+    }
+
+    let c_user = CString::new(user).ok()?;
+    let home = unsafe { passwd_home(getpwnam(c_user.as_ptr())) }?;
+    shell.homes.insert(user.to_string(), home.clone());
+    Some(home)
+}
+
+unsafe fn passwd_home(passwd: *mut libc::passwd) -> Option<String> {
+    if passwd.is_null() || (*passwd).pw_dir.is_null() {
+        return None;
+    }
+
+    Some(
+        CStr::from_ptr((*passwd).pw_dir)
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+// Expands the backquoted substitution at the start of `chars` into `result`,
+// returning the number of characters consumed.
+fn expand_backquote(chars: &[char], result: &mut String, shell: &mut Shell) -> usize {
+    match find_closing(chars, 1, '`') {
+        Some(end) => {
+            let inner: String = chars[1..end].iter().collect();
+            result.push_str(&command_substitution(&inner, shell));
+            end + 1
+        }
+        None => {
+            result.push('`');
+            1
+        }
+    }
+}
+
+pub fn expand_heredoc(body: &str, shell: &mut Shell) -> String {
+    let chars: Vec<char> = body.chars().collect();
+    let mut result = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => match chars[i + 1] {
+                '$' | '`' | '\\' => {
+                    result.push(chars[i + 1]);
+                    i += 2;
+                }
+                '\n' => i += 2,
+                _ => {
+                    result.push('\\');
+                    i += 1;
+                }
+            },
+            '$' => {
+                let (value, consumed) = expand_dollar(&chars[i..], shell);
+                result.push_str(&value);
+                i += consumed;
+            }
+            '`' => i += expand_backquote(&chars[i..], &mut result, shell),
+            c => {
+                result.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    result
+}
+
+// Expands the `$...` form at the start of `chars`, returning the value and
+// the number of characters consumed.
+fn expand_dollar(chars: &[char], shell: &mut Shell) -> (String, usize) {
+    match chars.get(1) {
+        Some('(') => match find_matching(chars, 2, ')') {
+            Some(end) => {
+                let inner: String = chars[2..end].iter().collect();
+                (command_substitution(&inner, shell), end + 1)
+            }
+            None => ("$".to_string(), 1),
+        },
+        Some('{') => match find_matching(chars, 2, '}') {
+            Some(end) => {
+                let name: String = chars[2..end].iter().collect();
+                (parameter(&name, shell), end + 1)
+            }
+            None => ("$".to_string(), 1),
+        },
+        Some(&c) if matches!(c, '$' | '?' | '!' | '#' | '@' | '*') || c.is_ascii_digit() => {
+            (parameter(&c.to_string(), shell), 2)
+        }
+        Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+            let len = chars[1..]
+                .iter()
+                .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                .count();
+            let name: String = chars[1..1 + len].iter().collect();
+            (parameter(&name, shell), 1 + len)
+        }
+        _ => ("$".to_string(), 1),
+    }
+}
+
+// Splits `name:-word` and the like into the parameter, whether the operator
+// has a colon, the operator and the word.
+fn parameter_operator(name: &str) -> Option<(&str, bool, char, &str)> {
+    let first = name.chars().next()?;
+    let mut length = if first.is_ascii_alphabetic() || first == '_' {
+        name.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')?
+    } else if first.is_ascii_digit() {
+        name.find(|c: char| !c.is_ascii_digit())?
+    } else if matches!(first, '$' | '?' | '!' | '#' | '@' | '*') {
+        1
+    } else {
+        return None;
+    };
+
+    if name[length..].starts_with('[') {
+        length += name[length..].find(']')? + 1;
+    }
+
+    let rest = &name[length..];
+    let (colon, rest) = match rest.strip_prefix(':') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+
+    let operator = rest
+        .chars()
+        .next()
+        .filter(|c| matches!(c, '-' | '=' | '+' | '?'))?;
+    Some((&name[..length], colon, operator, &rest[1..]))
+}
+
+// `${name-word}` uses the word when the parameter is unset, `${name=word}`
+// also assigns it, `${name+word}` uses it when the parameter is set and
+// `${name?word}` reports it as an error when unset. With a colon before the
+// operator, an empty parameter counts as unset.
+fn conditional_parameter(
+    name: &str,
+    colon: bool,
+    operator: char,
+    word: &str,
+    shell: &mut Shell,
+) -> String {
+    let value = parameter(name, shell);
+    let unset = !is_set(name, shell) || (colon && value.is_empty());
+
+    let word = |shell: &mut Shell| -> String {
+        let (chars, _) = expand(word, false, shell);
+        chars.iter().map(|e| e.c).collect()
+    };
+
+    match operator {
+        '-' if unset => word(shell),
+        '=' if unset => {
+            let word = word(shell);
+            let result = match subscript(name) {
+                Some((base, index)) => arithmetic::evaluate(index, &shell.variables)
+                    .and_then(|index| shell.variables.set_element(base, index, &word)),
+                None if is_name(name) => shell.variables.set(name, &word),
+                None => Err(format!("${}: cannot assign in this way", name)),
+            };
+            if let Err(e) = result {
+                eprintln!("rush: {}", e);
+            }
+            word
+        }
+        '+' if unset => String::new(),
+        '+' => word(shell),
+        '?' if unset => {
+            let message = word(shell);
+            match message.is_empty() {
+                true => eprintln!("rush: {}: parameter null or not set", name),
+                false => eprintln!("rush: {}: {}", name, message),
+            }
+            // Only an interactive shell carries on with the next command.
+            if !shell.job_control {
+                std::process::exit(1);
+            }
+            String::new()
+        }
+        _ => value,
+    }
+}
+
+// Whether a parameter is set, as opposed to set to an empty value.
+fn is_set(name: &str, shell: &mut Shell) -> bool {
+    if let Some((base, index)) = subscript(name) {
+        return match arithmetic::evaluate(index, &shell.variables) {
+            Ok(index) => shell.variables.element(base, index).is_some(),
+            Err(_) => false,
+        };
+    }
+
+    match name {
+        "@" | "*" => !shell.positional.is_empty(),
+        "!" => shell.jobs.last_pid().is_some(),
+        "$" | "?" | "#" => true,
+        _ if name.chars().all(|c| c.is_ascii_digit()) => match name.parse::<usize>() {
+            Ok(n) => n <= shell.positional.len(),
+            Err(_) => false,
+        },
+        _ => shell.variables.value(name).is_some(),
+    }
+}
+
+// The value of a variable or of a special parameter. `$@` and `$*` are
+// joined into one value here, as they are inside double quotes.
+fn parameter(name: &str, shell: &mut Shell) -> String {
+    // `${#name}` is the length of the value, or the number of elements for
+    // `${#name[@]}`.
+    if let Some(target) = name.strip_prefix('#').filter(|target| !target.is_empty()) {
+        return match subscript(target) {
+            Some((base, "@" | "*")) => elements(base, shell).len().to_string(),
+            _ => parameter(target, shell).chars().count().to_string(),
+        };
+    }
+
+    if let Some((prefix, kind)) = prefix_pattern(name) {
+        let separator = match kind {
+            "*" => ifs(shell).chars().take(1).collect(),
+            _ => " ".to_string(),
+        };
+        return prefixed_names(prefix, shell).join(&separator);
+    }
+
+    // `${!name}` expands the parameter whose name is the value of `name`,
+    // and is empty when that value doesn't name a parameter.
+    if let Some(target) = name.strip_prefix('!').filter(|target| !target.is_empty()) {
+        let (target, operation) = match parameter_operator(target) {
+            Some((reference, ..)) => target.split_at(reference.len()),
+            None => (target, ""),
+        };
+        let indirect = parameter(target, shell);
+        let valid = is_name(&indirect)
+            || (!indirect.is_empty() && indirect.chars().all(|c| c.is_ascii_digit()))
+            || matches!(indirect.as_str(), "@" | "*" | "#" | "?" | "$" | "!")
+            || subscript(&indirect).is_some_and(|(base, _)| is_name(base));
+        return match valid {
+            true => parameter(&format!("{}{}", indirect, operation), shell),
+            false => String::new(),
+        };
+    }
+
+    if let Some((target, colon, operator, word)) = parameter_operator(name) {
+        return conditional_parameter(target, colon, operator, word, shell);
+    }
+
+    if let Some((base, index)) = subscript(name) {
+        return match index {
+            "@" => elements(base, shell).join(" "),
+            "*" => {
+                let separator: String = ifs(shell).chars().take(1).collect();
+                elements(base, shell).join(&separator)
+            }
+            _ => match arithmetic::evaluate(&expand_heredoc(index, shell), &shell.variables) {
+                Ok(index) if shell.variables.index(base, index).is_none() => {
+                    eprintln!("rush: {}: bad array subscript", base);
+                    String::new()
+                }
+                Ok(index) => shell.variables.element(base, index).unwrap_or_default(),
+                Err(e) => {
+                    eprintln!("rush: {}", e);
+                    String::new()
+                }
+            },
+        };
+    }
+
+    match name {
+        "$" => std::process::id().to_string(),
+        "?" => shell.last_status.to_string(),
+        "!" => shell
+            .jobs
+            .last_pid()
+            .map(|pid| pid.to_string())
+            .unwrap_or_default(),
+        "#" => shell.positional.len().to_string(),
+        "@" => shell.positional.join(" "),
+        "*" => {
+            let separator: String = ifs(shell).chars().take(1).collect();
+            shell.positional.join(&separator)
+        }
+        _ if name.chars().all(|c| c.is_ascii_digit()) => match name.parse::<usize>() {
+            Ok(0) => shell.name.clone(),
+            Ok(n) => shell.positional.get(n - 1).cloned().unwrap_or_default(),
+            Err(_) => String::new(),
+        },
+        _ if is_name(name) || name == "-" => shell.variables.value(name).unwrap_or_default(),
+        // Forms like `${x#a}` or `${x:1}` that nothing above handles.
+        _ => {
+            eprintln!("rush: ${{{}}}: bad substitution", name);
+            shell.bad_substitution = true;
+            String::new()
+        }
+    }
+}
+
+// Decodes the backslash escapes of a `$'...'` string. Like in bash, the
+// string ends at a null byte, which no argument or variable could hold.
+fn ansi_c(value: &str) -> String {
+    let (mut result, _) = decode_escapes(value, false);
+    if let Some(end) = result.find('\0') {
+        result.truncate(end);
+    }
+    result
+}
+
+// Decodes backslash escapes, those of `$'...'` or, with `echo` set, those of
+// `echo -e`. These differ in that `\c` ends the output instead of making a
+// control character, octal escapes are written `\0nnn` rather than `\nnn`,
+// and quotes and `?` aren't escaped. Unknown escapes are kept as they are,
+// backslash included. The flag returned tells whether `\c` ended the output.
+pub(crate) fn decode_escapes(text: &str, echo: bool) -> (String, bool) {
+    let chars: Vec<char> = text.chars().collect();
+    let mut result = String::new();
+    let mut i = 0;
+
+    // Reads up to `max` digits of the given radix from `start`.
+    let number = |start: usize, max: usize, radix: u32| {
+        let digits = chars[start..]
+            .iter()
+            .take(max)
+            .take_while(|c| c.is_digit(radix))
+            .count();
+        let text: String = chars[start..start + digits].iter().collect();
+        (u32::from_str_radix(&text, radix).ok(), digits)
+    };
+
+    while i < chars.len() {
+        if chars[i] != '\\' || i + 1 == chars.len() {
+            result.push(chars[i]);
+            i += 1;
+            continue;
+        }
+
+        let escape = chars[i + 1];
+        i += 2;
+        let c = match escape {
+            'a' => '\x07',
+            'b' => '\x08',
+            'e' | 'E' => '\x1b',
+            'f' => '\x0c',
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'v' => '\x0b',
+            '\\' => '\\',
+            '\'' | '"' | '?' if !echo => escape,
+            'c' if echo => return (result, true),
+            '0' if echo => {
+                let (code, digits) = number(i, 3, 8);
+                i += digits;
+                code.and_then(char::from_u32).unwrap_or('\0')
+            }
+            '0'..='7' if !echo => {
+                let (code, digits) = number(i - 1, 3, 8);
+                i += digits - 1;
+                code.and_then(char::from_u32).unwrap_or('\0')
+            }
+            'x' | 'u' | 'U' => {
+                let max = match escape {
+                    'x' => 2,
+                    'u' => 4,
+                    _ => 8,
+                };
+                match number(i, max, 16) {
+                    (Some(code), digits) if char::from_u32(code).is_some() => {
+                        i += digits;
+                        char::from_u32(code).unwrap()
+                    }
+                    _ => {
+                        result.push('\\');
+                        escape
+                    }
+                }
+            }
+            'c' if i < chars.len() => {
+                i += 1;
+                char::from_u32(chars[i - 1].to_ascii_uppercase() as u32 ^ 0x40).unwrap()
+            }
+            _ => {
+                result.push('\\');
+                escape
+            }
+        };
+        result.push(c);
+    }
+
+    (result, false)
+}
+
+fn find_closing(chars: &[char], start: usize, close: char) -> Option<usize> {
+    (start..chars.len()).find(|&i| chars[i] == close)
+}
+
+// Finds the `)` or `}` closing the substitution, expansion or group whose
+// body starts at `start`. Quoted text, escaped characters, nested
+// expansions and the comments of a command are skipped over, so that
+// `$(echo ")")` and `${x:-"}"}` end where they should, and so is the `)`
+// ending the patterns of a `case` command.
+pub fn find_matching(chars: &[char], start: usize, close: char) -> Option<usize> {
+    let mut depth = 1;
+    let mut cases = 0;
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            'c' | 'e' if close == ')' && command_position(chars, start, i) => {
+                let word: String = chars[i..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                    .collect();
+                match word.as_str() {
+                    "case" => cases += 1,
+                    "esac" if cases > 0 => cases -= 1,
+                    _ => {}
+                }
+                i += word.len() - 1;
+            }
+            ')' if cases > 0 && depth == 1 => {}
+            '\\' => i += 1,
+            '\'' | '"' | '`' => i = find_quote_end(chars, i)?,
+            '$' if matches!(chars.get(i + 1), Some('(') | Some('{')) => {
+                let nested = if chars[i + 1] == '(' { ')' } else { '}' };
+                i = find_matching(chars, i + 2, nested)?;
+            }
+            '#' if close == ')' && (i == start || chars[i - 1].is_whitespace()) => {
+                while i + 1 < chars.len() && chars[i + 1] != '\n' {
+                    i += 1;
+                }
+            }
+            '(' if close == ')' => depth += 1,
+            c if c == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    None
+}
+
+// Whether a word starting at `i` is where a command could start, in the
+// command text beginning at `start`.
+fn command_position(chars: &[char], start: usize, i: usize) -> bool {
+    match chars[start..i]
+        .iter()
+        .rev()
+        .find(|c| **c != ' ' && **c != '\t')
+    {
+        Some(c) => matches!(c, ';' | '|' | '&' | '(' | '{' | '\n'),
+        None => true,
+    }
+}
+
+// Finds the quote closing the one at `start`. Backslashes escape characters
+// except in single quotes, and the expansions inside double quotes may hold
+// quotes of their own, as in `"$(echo "a b")"`.
+pub fn find_quote_end(chars: &[char], start: usize) -> Option<usize> {
+    let quote = chars[start];
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            c if c == quote => return Some(i),
+            '\\' if quote != '\'' => i += 1,
+            '$' if quote == '"' && matches!(chars.get(i + 1), Some('(') | Some('{')) => {
+                let close = if chars[i + 1] == '(' { ')' } else { '}' };
+                i = find_matching(chars, i + 2, close)?;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    None
+}
+
+// Runs the command of `<(...)` with its output going to a pipe, or that of
+// `>(...)` with its input coming from one, and returns the `/dev/fd` path
+// of the other end. The descriptor is inherited by the command using the
+// path, and closed once it is done.
+fn process_substitution(input: &str, output: bool, shell: &mut Shell) -> String {
+    let command = match shell.parse(input) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("rush: {}", e);
+            return String::new();
+        }
+    };
+
+    let mut fds = [0; 2];
+    if unsafe { pipe(fds.as_mut_ptr()) } != 0 {
+        let error = std::io::Error::last_os_error();
+        eprintln!("rush: pipe: {}", error_message(&error));
+        return String::new();
+    }
+
+    // The shell keeps the read end of `<(...)` and the write end of `>(...)`.
+    let (kept, given, target) = match output {
+        true => (fds[0], fds[1], 1),
+        false => (fds[1], fds[0], 0),
+    };
+
+    let pid = unsafe { fork() };
+    if pid < 0 {
+        let error = std::io::Error::last_os_error();
+        eprintln!("rush: fork: {}", error_message(&error));
+        unsafe {
+            close(kept);
+            close(given);
+        }
+        return String::new();
+    } else if pid == 0 {
+        unsafe {
+            shell.job_control = false;
+            shell.enter_subshell();
+            for (_, fd) in shell.substitutions.drain(..) {
+                close(fd);
+            }
+            close(kept);
+            dup2(given, target);
+            close(given);
+            let status = command.execute(shell);
+            let _ = std::io::stdout().flush();
+            exit(status);
+        }
+    }
+
+    unsafe { close(given) };
+    shell.substitutions.push((pid, kept));
+    format!("/dev/fd/{}", kept)
+}
+
+pub fn command_substitution(input: &str, shell: &mut Shell) -> String {
+    if let Some(path) = input_file(input) {
+        return match read_file(&path, shell) {
+            Ok(output) => {
+                substituted(0, shell);
+                output
+            }
+            Err(e) => {
+                eprintln!("rush: {}", e);
+                substituted(1, shell);
+                String::new()
+            }
+        };
+    }
+
+    let command = match shell.parse(input) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("rush: {}", e);
+            substituted(2, shell);
+            return String::new();
+        }
+    };
+
+    let mut fds = [0; 2];
+    unsafe {
+        if pipe2(fds.as_mut_ptr(), O_CLOEXEC) != 0 {
+            let error = std::io::Error::last_os_error();
+            eprintln!("rush: pipe: {}", error_message(&error));
+            return String::new();
+        }
+    }
+
+    let (read_end, write_end) = (fds[0], fds[1]);
+    let pid = unsafe { fork() };
+    if pid < 0 {
+        let error = std::io::Error::last_os_error();
+        eprintln!("rush: fork: {}", error_message(&error));
+        unsafe {
+            close(read_end);
+            close(write_end);
+        }
+        return String::new();
+    } else if pid == 0 {
+        unsafe {
+            shell.job_control = false;
+            shell.enter_subshell();
+            close(read_end);
+            dup2(write_end, 1);
+            close(write_end);
+            let status = command.execute(shell);
+            let _ = std::io::stdout().flush();
+            exit(status);
+        }
+    }
+
+    unsafe { close(write_end) };
+
+    let mut output = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        let n = unsafe { read(read_end, buffer.as_mut_ptr() as *mut _, buffer.len()) };
+        if n <= 0 {
+            break;
+        }
+        output.extend_from_slice(&buffer[..n as usize]);
+    }
+
+    let mut status: c_int = 0;
+    unsafe {
+        close(read_end);
+        waitpid(pid, &mut status, 0);
+    }
+    substituted(exit_status(status), shell);
+
+    substitution_output(output)
+}
+
+// Records the status of a command substitution, which becomes `$?` and the
+// status of a command made only of assignments.
+fn substituted(status: i32, shell: &mut Shell) {
+    shell.last_status = status;
+    shell.substitution_status = Some(status);
+}
+
+// Turns the bytes a substitution captured into its value. Values are strings
+// everywhere in the shell, so bytes that aren't valid UTF-8 are replaced by
+// U+FFFD, and null bytes, which no argument or variable can hold, are
+// dropped like bash does. Trailing newlines are removed.
+fn substitution_output(mut output: Vec<u8>) -> String {
+    let len = output.len();
+    output.retain(|&byte| byte != 0);
+    if output.len() != len {
+        eprintln!("rush: warning: command substitution: ignored null byte in input");
+    }
+
+    let mut output = String::from_utf8_lossy(&output).into_owned();
+    while output.ends_with('\n') {
+        output.pop();
+    }
+    output
+}
+
+// The file of a substitution made of a single input redirection, `$(< file)`.
+fn input_file(input: &str) -> Option<String> {
+    let mut tokens = Lexer::new(input.to_string()).tokens();
+    tokens.retain(|token| *token != Token::Newline);
+    match tokens.as_slice() {
+        [Token::RedirectOperator(RedirectOperator::Input), Token::Word(path)] => Some(path.clone()),
+        _ => None,
+    }
+}
+
+// Reads the file of `$(< file)` without forking, stripping trailing newlines
+// like any other substitution.
+fn read_file(path: &str, shell: &mut Shell) -> Result<String, String> {
+    let mut fields = expand_word(path, shell)?;
+    if fields.len() != 1 {
+        return Err(format!("{}: ambiguous redirect", path));
+    }
+    let path = fields.remove(0);
+
+    match std::fs::read(&path) {
+        Ok(contents) => Ok(substitution_output(contents)),
+        Err(e) => Err(format!("{}: {}", path, error_message(&e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell::tests::{run, scratch};
+
+    #[test]
+    fn split_fields_on_ifs() {
+        for (value, ifs, limit, fields) in [
+            ("a b  c", " \t\n", 0, vec!["a", "b", "c"]),
+            ("  a b  ", " \t\n", 0, vec!["a", "b"]),
+            ("a\tb\nc", " \t\n", 0, vec!["a", "b", "c"]),
+            ("a::b", ":", 0, vec!["a", "", "b"]),
+            (":a:", ":", 0, vec!["", "a"]),
+            ("a b", "", 0, vec!["a b"]),
+            ("", " \t\n", 0, vec![]),
+            ("a b c  ", " \t\n", 2, vec!["a", "b c"]),
+            ("a:b:c", ":", 2, vec!["a", "b:c"]),
+        ] {
+            assert_eq!(
+                split_ifs(value, ifs, limit),
+                fields,
+                "{:?} split on {:?}",
+                value,
+                ifs
+            );
+        }
+    }
+
+    #[test]
+    fn split_unquoted_expansions() {
+        let mut shell = Shell::new();
+        shell.eval("IFS=:; x=a::b; set -- $x; n=$#; set -- \"$x\"; m=$#");
+        assert_eq!(shell.variables.get("n"), Some("3"));
+        assert_eq!(shell.variables.get("m"), Some("1"));
+    }
+
+    #[test]
+    fn split_fields_on_ifs_whitespace() {
+        for (value, ifs, fields) in [
+            ("a : b", ": ", vec!["a", "b"]),
+            ("a :: b", ": ", vec!["a", "", "b"]),
+            (" :a", ": ", vec!["", "a"]),
+            ("a  b", ": ", vec!["a", "b"]),
+            ("a\u{a0}b c", " ", vec!["a\u{a0}b", "c"]),
+            ("a\rb", " \t\n", vec!["a\rb"]),
+            ("a\r\rb", "\r", vec!["a", "", "b"]),
+            ("a\u{a0}\u{a0}b", "\u{a0}", vec!["a", "", "b"]),
+        ] {
+            assert_eq!(
+                split_ifs(value, ifs, 0),
+                fields,
+                "{:?} split on {:?}",
+                value,
+                ifs
+            );
+        }
+    }
+
+    #[test]
+    fn bad_substitution() {
+        let mut shell = Shell::new();
+        for word in ["${x#a}", "${x%b}", "${x/a/z}", "${x:1}"] {
+            assert_eq!(
+                shell.eval(&format!("x=abc; y=ok; y={}", word)),
+                1,
+                "{}",
+                word
+            );
+            assert_eq!(shell.variables.get("y"), Some("ok"));
+            assert_eq!(shell.eval(&format!("y={} true", word)), 1, "{}", word);
+        }
+        assert_eq!(shell.eval("y=${x:-a}${#x}${x[0]}${1}"), 0);
+        assert_eq!(shell.variables.get("y"), Some("abc3abc"));
+    }
+
+    #[test]
+    fn quote_removal_comes_last() {
+        let mut shell = Shell::new();
+        shell.eval("x='a b'; set -- $x; n=$#; set -- \"$x\"; m=$#");
+        assert_eq!(shell.variables.get("n"), Some("2"));
+        assert_eq!(shell.variables.get("m"), Some("1"));
+
+        // Quotes that come out of an expansion are plain characters.
+        shell.eval("x='\"a b\"'; set -- $x; first=$1; second=$2");
+        assert_eq!(shell.variables.get("first"), Some("\"a"));
+        assert_eq!(shell.variables.get("second"), Some("b\""));
+    }
+
+    #[test]
+    fn quoting_modes() {
+        let mut shell = Shell::new();
+        shell.eval("x='1  2'");
+        for (word, fields) in [
+            ("'$x'", vec!["$x"]),
+            ("\"$x\"", vec!["1  2"]),
+            ("\\$x", vec!["$x"]),
+            ("$x", vec!["1", "2"]),
+            ("\"a \\$x \\\" \\q\"", vec!["a $x \" \\q"]),
+            ("a'$x'\"$x\"\\ \\$x", vec!["a$x1  2 $x"]),
+            ("'a'$x\"b\"", vec!["a1", "2b"]),
+        ] {
+            assert_eq!(
+                expand_word(word, &mut shell),
+                Ok(fields.iter().map(|f| f.to_string()).collect()),
+                "{}",
+                word
+            );
+        }
+    }
+
+    #[test]
+    fn positional_parameter_lists() {
+        let mut shell = Shell::new();
+        for (script, word, fields) in [
+            ("set -- 'a b' '' ' c '", "\"$@\"", vec!["a b", "", " c "]),
+            (
+                "set -- 'a b' '' ' c '",
+                "x\"$@\"y",
+                vec!["xa b", "", " c y"],
+            ),
+            ("set -- 'a b' '' ' c '", "$@", vec!["a", "b", "c"]),
+            ("set --", "\"$@\"", vec![]),
+            ("set --", "\"$*\"", vec![""]),
+            ("set -- a 'b c' d", "\"$*\"", vec!["a b c d"]),
+            ("set -- a 'b c' d; IFS=:", "\"$*\"", vec!["a:b c:d"]),
+            ("set -- a 'b c' d; IFS=", "\"$*\"", vec!["ab cd"]),
+            ("set -- a 'b c' d; unset IFS", "\"$*\"", vec!["a b c d"]),
+        ] {
+            shell.eval(script);
+            assert_eq!(
+                expand_word(word, &mut shell),
+                Ok(fields.iter().map(|f| f.to_string()).collect()),
+                "{} with {}",
+                word,
+                script
+            );
+            shell.eval("unset IFS");
+        }
+    }
+
+    #[test]
+    fn substitution_of_a_file() {
+        let dir = scratch("substitute-file", &[]);
+        for contents in ["a b\n", "a\n\nb\n\n\n", "", "\n", "no newline"] {
+            std::fs::write(dir.join("f"), contents).unwrap();
+            let script = format!(
+                "cd {}; x=$(<f); y=$(cat f); [ \"$x\" = \"$y\" ] && echo \"[$x]\"",
+                dir.display()
+            );
+            let expected = format!("[{}]\n", contents.trim_end_matches('\n'));
+            assert_eq!(run(&script), (0, expected), "{:?}", contents);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn substitution_of_builtins() {
+        let script = "x=$(echo hi); cd /; y=$(pwd); z=$(type cd); echo \"$x|$y|$z\"";
+        assert_eq!(run(script), (0, "hi|/|cd is a shell builtin\n".to_string()));
+        let (_, output) = run("x=$(type ls); echo \"$x\"");
+        assert!(output.starts_with("ls is /"), "{:?}", output);
+
+        // The builtin ran in the substitution, not in the shell.
+        assert_eq!(run("x=$(cd /); pwd").1, run("pwd").1);
+    }
+
+    #[test]
+    fn indirect_expansion() {
+        let mut shell = Shell::new();
+        shell.eval("x=y; y=hello; n=2; u=nothing; set -- a b c; rush_q2=2 rush_q1=1");
+        for (word, fields) in [
+            ("${!x}", vec!["hello"]),
+            ("${!n}", vec!["b"]),
+            ("\"${!u}\"", vec![""]),
+            ("${!rush_q*}", vec!["rush_q1", "rush_q2"]),
+            ("\"${!rush_q@}\"", vec!["rush_q1", "rush_q2"]),
+            ("\"${!rush_q*}\"", vec!["rush_q1 rush_q2"]),
+            ("${!rush_none*}", vec![]),
+        ] {
+            assert_eq!(
+                expand_word(word, &mut shell),
+                Ok(fields.iter().map(|f| f.to_string()).collect()),
+                "{}",
+                word
+            );
+        }
+    }
+
+    #[test]
+    fn substitution_of_invalid_utf8() {
+        let output = substitution_output(b"a\xffb\xc0\0c\n\n".to_vec());
+        assert_eq!(output, "a\u{fffd}b\u{fffd}c");
+
+        let script =
+            "x=$(printf 'a\\377b\\300\\n'); echo ${#x}; y=$(head -c 64 /dev/urandom); echo $?";
+        assert_eq!(run(script), (0, "4\n0\n".to_string()));
+    }
+
+    #[test]
+    fn assignment_takes_substitution_status() {
+        let script = "x=$(false); echo $?; x=$(echo hi; exit 3); echo $? $x";
+        assert_eq!(run(script), (0, "1\n3 hi\n".to_string()));
+
+        // The last substitution counts, and a command's status wins.
+        let script = "x=$(exit 3) y=$(true); echo $?; x=$(false) true; echo $?";
+        assert_eq!(run(script), (0, "0\n0\n".to_string()));
+    }
+
+    #[test]
+    fn closing_parenthesis_of_a_substitution() {
+        for (text, end) in [
+            ("echo a) b", 6),
+            ("echo $(echo a)) b", 14),
+            ("echo ')' \")\" \\)) b", 15),
+            ("echo \"$(echo \")\")\") b", 18),
+            ("case a in a) echo;; esac) b", 24),
+            ("echo # )\n) b", 9),
+            ("echo ${x:-)}) b", 12),
+        ] {
+            let chars: Vec<char> = text.chars().collect();
+            assert_eq!(find_matching(&chars, 0, ')'), Some(end), "{:?}", text);
+        }
+        let chars: Vec<char> = "echo $(echo a)".chars().collect();
+        assert_eq!(find_matching(&chars, 0, ')'), None);
+    }
+
+    #[test]
+    fn nested_substitutions() {
+        for (script, output) in [
+            ("echo $(echo $(echo hi))", "hi\n"),
+            ("echo $(echo $(echo $(echo deep)))", "deep\n"),
+            ("echo \"$(echo \"a  b\")\"", "a  b\n"),
+            ("set -- \"$(echo \"a b\")\"; echo $#", "1\n"),
+            ("echo \"$(echo \"$(echo 'x  y')\")\"", "x  y\n"),
+            ("echo $(echo ')' \"(\")", ") (\n"),
+            ("echo $(echo `echo back`)", "back\n"),
+            ("x=$(case a in a) echo case;; esac); echo $x", "case\n"),
+        ] {
+            assert_eq!(run(script), (0, output.to_string()), "{}", script);
+        }
+    }
+
+    #[test]
+    fn heredoc_bodies() {
+        let mut shell = Shell::new();
+        shell.eval("x=1");
+        let body = "'$x' \"$x\" \\$x \\q\n";
+        assert_eq!(expand_heredoc(body, &mut shell), "'1' \"1\" $x \\q\n");
+    }
+
+    #[test]
+    fn ansi_c_escapes() {
+        for (text, value) in [
+            (r"a\nb", "a\nb"),
+            (r"a\tb", "a\tb"),
+            (r"a\\b", "a\\b"),
+            (r"a\'b", "a'b"),
+            (r"\x41\x4a\x4", "AJ\x04"),
+            (r"\0101", "\x081"),
+            (r"\101\60", "A0"),
+            (r"\u00e9\u263A", "é☺"),
+            (r"a\0b", "a"),
+            (r"a\x00b", "a"),
+            (r"a\u0000b", "a"),
+        ] {
+            assert_eq!(ansi_c(text), value, "{:?}", text);
+        }
+
+        let mut shell = Shell::new();
+        let fields = expand_word(r"$'a b\t'$'\x41\0c'", &mut shell);
+        assert_eq!(fields, Ok(vec!["a b\tA".to_string()]));
+    }
+
+    #[test]
+    fn escapes_of_both_forms() {
+        // The text, then what `$'...'` and `echo -e` make of it.
+        for (text, ansi_c, echo) in [
+            (r"a\nb\tc", "a\nb\tc", "a\nb\tc"),
+            (r"\\ \a\e\E", "\\ \x07\x1b\x1b", "\\ \x07\x1b\x1b"),
+            (r"\x41é\U0001F600", "Aé😀", "Aé😀"),
+            (r"\101\0101", "A\u{8}1", "\\101A"),
+            (r#"\'\"\?"#, "'\"?", r#"\'\"\?"#),
+            (r"\q\x\9", "\\q\\x\\9", "\\q\\x\\9"),
+            (r"trailing\", "trailing\\", "trailing\\"),
+        ] {
+            assert_eq!(
+                decode_escapes(text, false),
+                (ansi_c.to_string(), false),
+                "{:?}",
+                text
+            );
+            assert_eq!(
+                decode_escapes(text, true),
+                (echo.to_string(), false),
+                "{:?}",
+                text
+            );
+        }
+
+        // `\c` makes a control character, or ends the output of `echo`.
+        assert_eq!(
+            decode_escapes(r"a\cAb", false),
+            ("a\x01b".to_string(), false)
+        );
+        assert_eq!(decode_escapes(r"a\cAb", true), ("a".to_string(), true));
+    }
+}
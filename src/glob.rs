@@ -0,0 +1,426 @@
+use std::fs;
+
+use crate::options::Options;
+
+type Pattern = Vec<(char, bool)>;
+
+// Patterns are sequences of characters paired with whether they were quoted,
+// in which case they only ever match themselves.
+pub fn is_pattern(pattern: &[(char, bool)], options: &Options) -> bool {
+    let extglob = options.enabled("extglob");
+
+    pattern.iter().enumerate().any(|(i, &(c, quoted))| {
+        !quoted
+            && (matches!(c, '*' | '?' | '[')
+                || (extglob
+                    && matches!(c, '+' | '@' | '!')
+                    && pattern.get(i + 1) == Some(&('(', false))))
+    })
+}
+
+// Expands a pattern into the list of existing paths it matches, sorted by
+// byte value like in the C locale, which is empty when nothing matches.
+pub fn glob(pattern: &[(char, bool)], options: &Options) -> Vec<String> {
+    let absolute = matches!(pattern.first(), Some(('/', _)));
+    let directories_only = matches!(pattern.last(), Some(('/', _)));
+
+    let segments: Vec<&[(char, bool)]> = pattern
+        .split(|&(c, _)| c == '/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    let mut paths = vec![if absolute {
+        String::from("/")
+    } else {
+        String::new()
+    }];
+
+    for (i, segment) in segments.iter().enumerate() {
+        let last = i + 1 == segments.len();
+        let mut next = vec![];
+
+        for path in &paths {
+            if !is_pattern(segment, options) {
+                let name: String = segment.iter().map(|&(c, _)| c).collect();
+                let candidate = join(path, &name);
+                if fs::symlink_metadata(&candidate).is_ok() {
+                    next.push(candidate);
+                }
+                continue;
+            }
+
+            let directory = if path.is_empty() { "." } else { path.as_str() };
+            let entries = match fs::read_dir(directory) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+
+            // Hidden entries only match when the pattern starts with a dot.
+            let hidden = matches!(segment.first(), Some(('.', _)));
+
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') && !hidden {
+                    continue;
+                }
+
+                let chars: Vec<char> = name.chars().collect();
+                if !match_pattern(segment, &chars, Flags::new(options)) {
+                    continue;
+                }
+
+                let candidate = join(path, &name);
+                if (!last || directories_only)
+                    && !fs::metadata(&candidate).is_ok_and(|m| m.is_dir())
+                {
+                    continue;
+                }
+                next.push(candidate);
+            }
+        }
+
+        paths = next;
+    }
+
+    if segments.is_empty() {
+        return vec![];
+    }
+
+    if directories_only {
+        for path in paths.iter_mut() {
+            path.push('/');
+        }
+    }
+
+    paths.sort();
+    paths
+}
+
+fn join(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else if path.ends_with('/') {
+        format!("{}{}", path, name)
+    } else {
+        format!("{}/{}", path, name)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Flags {
+    extglob: bool,
+    nocase: bool,
+}
+
+impl Flags {
+    // The flags of pathname expansion, the only matching `nocaseglob`
+    // applies to.
+    fn new(options: &Options) -> Flags {
+        Flags {
+            extglob: options.enabled("extglob"),
+            nocase: options.enabled("nocaseglob"),
+        }
+    }
+}
+
+// Matches a name the way `case` does, always minding case.
+pub fn matches(pattern: &[(char, bool)], name: &[char], options: &Options) -> bool {
+    let flags = Flags {
+        nocase: false,
+        ..Flags::new(options)
+    };
+    match_pattern(pattern, name, flags)
+}
+
+fn match_pattern(pattern: &[(char, bool)], name: &[char], flags: Flags) -> bool {
+    if flags.extglob {
+        if let Some((kind, alternatives, len)) = extended(pattern) {
+            let rest = &pattern[len..];
+            return (0..=name.len()).any(|i| {
+                let (prefix, suffix) = name.split_at(i);
+                let matched = match kind {
+                    '@' => alternatives.iter().any(|a| match_pattern(a, prefix, flags)),
+                    '?' => {
+                        prefix.is_empty()
+                            || alternatives.iter().any(|a| match_pattern(a, prefix, flags))
+                    }
+                    '*' => repeat(&alternatives, prefix, 0, flags),
+                    '+' => repeat(&alternatives, prefix, 1, flags),
+                    _ => !alternatives.iter().any(|a| match_pattern(a, prefix, flags)),
+                };
+
+                matched && match_pattern(rest, suffix, flags)
+            });
+        }
+    }
+
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(&('*', false)) => {
+            (0..=name.len()).any(|i| match_pattern(&pattern[1..], &name[i..], flags))
+        }
+        Some(&('?', false)) => !name.is_empty() && match_pattern(&pattern[1..], &name[1..], flags),
+        Some(&('[', false)) => match bracket(pattern, name.first(), flags) {
+            Some((matched, len)) => matched && match_pattern(&pattern[len..], &name[1..], flags),
+            None => name.first() == Some(&'[') && match_pattern(&pattern[1..], &name[1..], flags),
+        },
+        Some(&(c, _)) => match name.first() {
+            Some(&n) => same(c, n, flags) && match_pattern(&pattern[1..], &name[1..], flags),
+            None => false,
+        },
+    }
+}
+
+fn same(a: char, b: char, flags: Flags) -> bool {
+    a == b || (flags.nocase && a.to_lowercase().eq(b.to_lowercase()))
+}
+
+// Whether `name` is made of at least `min` consecutive matches of any of the
+// alternatives.
+fn repeat(alternatives: &[Pattern], name: &[char], min: usize, flags: Flags) -> bool {
+    if name.is_empty() {
+        return min == 0;
+    }
+
+    (1..=name.len()).any(|i| {
+        alternatives
+            .iter()
+            .any(|a| match_pattern(a, &name[..i], flags))
+            && repeat(alternatives, &name[i..], min.saturating_sub(1), flags)
+    })
+}
+
+// Parses an extended pattern like `+(a|b)` at the start of `pattern` into its
+// kind, its alternatives and its length.
+fn extended(pattern: &[(char, bool)]) -> Option<(char, Vec<Pattern>, usize)> {
+    let kind = match pattern {
+        [(c, false), ('(', false), ..] if matches!(c, '?' | '*' | '+' | '@' | '!') => *c,
+        _ => return None,
+    };
+
+    let mut alternatives = vec![];
+    let mut current = vec![];
+    let mut depth = 0;
+
+    for (i, &(c, quoted)) in pattern.iter().enumerate().skip(2) {
+        match c {
+            '(' if !quoted => depth += 1,
+            ')' if !quoted && depth == 0 => {
+                alternatives.push(current);
+                return Some((kind, alternatives, i + 1));
+            }
+            ')' if !quoted => depth -= 1,
+            '|' if !quoted && depth == 0 => {
+                alternatives.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push((c, quoted));
+    }
+
+    None
+}
+
+// Matches a `[...]` set at the start of `pattern` against `c`, returning
+// whether it matched and the length of the set, or `None` when the bracket is
+// not closed and so stands for itself. Sets hold characters, ranges like
+// `a-z` and classes like `[:digit:]`, and are negated by a leading `!` or
+// `^`. A `]` right after the opening bracket and a `-` at either end of the
+// set stand for themselves.
+fn bracket(pattern: &[(char, bool)], c: Option<&char>, flags: Flags) -> Option<(bool, usize)> {
+    let negated = matches!(pattern.get(1), Some(('!', false)) | Some(('^', false)));
+    let start = if negated { 2 } else { 1 };
+
+    let mut matched = false;
+    let mut i = start;
+    loop {
+        let &(set, quoted) = pattern.get(i)?;
+        if set == ']' && !quoted && i > start {
+            break;
+        }
+
+        if set == '[' && !quoted && pattern.get(i + 1) == Some(&(':', false)) {
+            if let Some(length) = class_length(&pattern[i + 2..]) {
+                let name: String = pattern[i + 2..i + 2 + length].iter().map(|p| p.0).collect();
+                matched |= c.is_some_and(|&c| in_class(&name, c));
+                i += length + 4;
+                continue;
+            }
+        }
+
+        let range_end = match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some(('-', false)), Some(&(end, quoted))) if end != ']' || quoted => Some(end),
+            _ => None,
+        };
+
+        match range_end {
+            Some(end) => {
+                matched |= c.is_some_and(|&c| in_range(set, end, c, flags));
+                i += 3;
+            }
+            None => {
+                matched |= c.is_some_and(|&c| same(set, c, flags));
+                i += 1;
+            }
+        }
+    }
+
+    Some((c.is_some() && matched != negated, i + 1))
+}
+
+// The length of the name of a `[:class:]` whose name starts `pattern`.
+fn class_length(pattern: &[(char, bool)]) -> Option<usize> {
+    let length = pattern
+        .iter()
+        .position(|&(c, _)| !c.is_ascii_alphabetic())?;
+    match (pattern.get(length), pattern.get(length + 1)) {
+        (Some((':', false)), Some((']', false))) => Some(length),
+        _ => None,
+    }
+}
+
+fn in_class(name: &str, c: char) -> bool {
+    match name {
+        "alnum" => c.is_alphanumeric(),
+        "alpha" => c.is_alphabetic(),
+        "blank" => c == ' ' || c == '\t',
+        "cntrl" => c.is_control(),
+        "digit" => c.is_ascii_digit(),
+        "graph" => !c.is_control() && !c.is_whitespace(),
+        "lower" => c.is_lowercase(),
+        "print" => !c.is_control(),
+        "punct" => c.is_ascii_punctuation(),
+        "space" => c.is_whitespace(),
+        "upper" => c.is_uppercase(),
+        "xdigit" => c.is_ascii_hexdigit(),
+        _ => false,
+    }
+}
+
+fn in_range(start: char, end: char, c: char, flags: Flags) -> bool {
+    if (start..=end).contains(&c) {
+        return true;
+    }
+
+    flags.nocase
+        && c.to_lowercase()
+            .chain(c.to_uppercase())
+            .any(|c| (start..=end).contains(&c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell::tests::{run, scratch};
+
+    fn matches_str(pattern: &str, name: &str) -> bool {
+        let pattern: Pattern = pattern.chars().map(|c| (c, false)).collect();
+        let name: Vec<char> = name.chars().collect();
+        matches(&pattern, &name, &Options::default())
+    }
+
+    #[test]
+    fn bracket_expressions() {
+        for (pattern, matching, other) in [
+            ("[abc]", "b", "d"),
+            ("[a-c]", "b", "d"),
+            ("[a-cx-z]", "y", "m"),
+            ("[0-9][0-9]", "42", "4a"),
+            ("[!abc]", "d", "a"),
+            ("[^abc]", "d", "c"),
+            ("[!a-c]", "z", "b"),
+            ("[[:digit:]]", "7", "x"),
+            ("[[:alpha:][:digit:]]", "q", "-"),
+            ("[![:space:]]", "x", " "),
+            ("[]]", "]", "a"),
+            ("[]a]", "a", "b"),
+            ("[!]]", "a", "]"),
+            ("[-a]", "-", "b"),
+            ("[a-]", "-", "b"),
+            ("[!-]", "a", "-"),
+            ("[abc", "[abc", "a"),
+            ("x[ab]y", "xby", "xy"),
+        ] {
+            assert!(
+                matches_str(pattern, matching),
+                "{:?} ~ {:?}",
+                pattern,
+                matching
+            );
+            assert!(!matches_str(pattern, other), "{:?} !~ {:?}", pattern, other);
+        }
+    }
+
+    #[test]
+    fn bracket_quoted_and_nocase() {
+        let pattern: Pattern = vec![
+            ('[', false),
+            ('a', false),
+            ('-', true),
+            ('c', false),
+            (']', false),
+        ];
+        let mut options = Options::default();
+        assert!(matches(&pattern, &['-'], &options));
+        assert!(!matches(&pattern, &['b'], &options));
+
+        let nocase = Flags {
+            extglob: false,
+            nocase: true,
+        };
+        assert!(match_pattern(&pattern, &['A'], nocase));
+        let range: Pattern = "[a-c]".chars().map(|c| (c, false)).collect();
+        assert!(match_pattern(&range, &['B'], nocase));
+
+        // `nocaseglob` is for pathname expansion only.
+        options.set_shopt("nocaseglob", true).unwrap();
+        assert!(!matches(&range, &['B'], &options));
+    }
+
+    #[test]
+    fn files_named_like_patterns() {
+        let files = ["*", "?", "a", "a*b", "a?b", "axb"];
+        let dir = scratch("glob-literal", &files);
+        let script = format!("cd {}; echo \\* '?' [*] [?]; echo *", dir.display());
+        let (_, output) = run(&script);
+        assert_eq!(output, "* ? * ?\n* ? a a*b a?b axb\n");
+
+        // Quoted characters match only themselves, the rest still globs.
+        let script = format!("cd {}; echo \"a*b\" a\\?b; echo 'a*'* a?b", dir.display());
+        assert_eq!(run(&script).1, "a*b a?b\na*b a*b a?b axb\n");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn unmatched_patterns() {
+        let dir = scratch("glob-unmatched", &["a.txt"]);
+        let cd = format!("cd {}; ", dir.display());
+        let script = cd.clone() + "echo x *.none y";
+        assert_eq!(run(&script), (0, "x *.none y\n".to_string()));
+        let script = cd.clone() + "shopt -s nullglob; echo x *.none y; set -- *.none; echo $#";
+        assert_eq!(run(&script), (0, "x y\n0\n".to_string()));
+
+        // The command doesn't run, and the next one sees the failure.
+        let script = cd + "shopt -s failglob; echo x *.none y; echo $?; echo *.txt";
+        assert_eq!(run(&script), (0, "1\na.txt\n".to_string()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn dotfiles_and_order() {
+        let files = ["b", "B", "a", "_c", "10", "9", ".x"];
+        let dir = scratch("glob-dotfiles", &files);
+        std::fs::create_dir(dir.join("d")).unwrap();
+        std::fs::write(dir.join("d/.y"), "").unwrap();
+        std::fs::write(dir.join("d/z"), "").unwrap();
+
+        let script = format!(
+            "cd {}; echo *; echo .*; echo ?x; echo d/*; echo d/.*",
+            dir.display()
+        );
+        let (_, output) = run(&script);
+        assert_eq!(output, "10 9 B _c a b d\n.x\n?x\nd/z\nd/.y\n");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,25 +1,70 @@
-use libc::c_char;
+use libc::{c_char, c_int, c_void, EINTR};
 use std::ffi::{CStr, CString};
 
+use crate::command::c_string;
+
+#[repr(C)]
+struct HistEntry {
+    line: *mut c_char,
+    timestamp: *mut c_char,
+    data: *mut c_void,
+}
+
 extern "C" {
     fn readline(prompt: *const c_char) -> *mut c_char;
     fn add_history(line: *const c_char);
+    fn history_get(offset: c_int) -> *mut HistEntry;
     fn free(ptr: *mut c_char);
+
+    static history_base: c_int;
+    static history_length: c_int;
 }
 
 pub fn input_read(prompt: String) -> Option<String> {
-    let prompt = CString::new(prompt).unwrap();
+    let prompt = c_string(&prompt);
 
     unsafe {
-        let input = readline(prompt.as_ptr());
+        // A signal arriving while readline blocks can make it return as if
+        // the input had ended, so only a genuine end of input is reported.
+        let input = loop {
+            *libc::__errno_location() = 0;
+            let input = readline(prompt.as_ptr());
+            if !input.is_null() || *libc::__errno_location() != EINTR {
+                break input;
+            }
+        };
 
         if input.is_null() {
             None
         } else {
-            add_history(input);
             let command = CStr::from_ptr(input).to_string_lossy().into_owned();
             free(input);
             Some(command)
         }
     }
 }
+
+// Records a command in the history. A command read over several lines is
+// added once, as the whole text, so that recalling it brings back all of it.
+pub fn input_history(command: &str) {
+    let Ok(command) = CString::new(command) else {
+        return;
+    };
+    unsafe { add_history(command.as_ptr()) };
+}
+
+// The commands in the history along with their numbers, oldest first.
+pub fn input_history_entries() -> Vec<(usize, String)> {
+    unsafe {
+        (history_base..history_base + history_length)
+            .filter_map(|number| {
+                let entry = history_get(number);
+                if entry.is_null() || (*entry).line.is_null() {
+                    return None;
+                }
+                let line = CStr::from_ptr((*entry).line).to_string_lossy();
+                Some((number as usize, line.into_owned()))
+            })
+            .collect()
+    }
+}
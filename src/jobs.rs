@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+use libc::{c_int, pid_t, siginfo_t, waitid, EINTR, P_ALL, WEXITED, WNOHANG, WNOWAIT};
+use libc::{WEXITSTATUS, WIFEXITED, WIFSIGNALED, WTERMSIG};
+
+pub struct Job {
+    pub id: usize,
+    pub pgid: pid_t,
+    pub pids: Vec<pid_t>,           // One per pipeline stage
+    pub statuses: Vec<Option<i32>>, // Filled in as stages are reaped
+}
+
+// The jobs started in the background that have not been waited for yet.
+#[derive(Default)]
+pub struct Jobs {
+    jobs: Vec<Job>,
+    last_pid: Option<pid_t>, // `$!`
+}
+
+// Waits like `waitpid`, retrying when a signal interrupts the call.
+pub fn waitpid(pid: pid_t, status: &mut c_int, options: c_int) -> pid_t {
+    loop {
+        let result = unsafe { libc::waitpid(pid, status, options) };
+        if result != -1 || std::io::Error::last_os_error().raw_os_error() != Some(EINTR) {
+            return result;
+        }
+    }
+}
+
+pub fn exit_status(status: c_int) -> i32 {
+    if WIFEXITED(status) {
+        WEXITSTATUS(status)
+    } else if WIFSIGNALED(status) {
+        128 + WTERMSIG(status)
+    } else {
+        1
+    }
+}
+
+impl Jobs {
+    pub fn add(&mut self, pgid: pid_t, pids: Vec<pid_t>) -> usize {
+        let id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+
+        self.last_pid = pids.last().copied();
+        self.jobs.push(Job {
+            id,
+            pgid,
+            statuses: vec![None; pids.len()],
+            pids,
+        });
+
+        id
+    }
+
+    pub fn last_pid(&self) -> Option<pid_t> {
+        self.last_pid
+    }
+
+    // Blocks until every stage of the job is reaped, removing it from the
+    // table and returning the status of its last stage.
+    pub fn wait_job(&mut self, id: usize) -> Option<i32> {
+        let index = self.jobs.iter().position(|job| job.id == id)?;
+        let job = self.jobs.remove(index);
+
+        let mut status = 0;
+        for &pid in &job.pids {
+            let mut raw = 0;
+            if waitpid(pid, &mut raw, 0) == pid {
+                status = exit_status(raw);
+            }
+        }
+
+        Some(job.statuses.last().copied().flatten().unwrap_or(status))
+    }
+
+    // Blocks until any one job completes, removing it from the table and
+    // returning its id and the status of its last stage. Jobs that already
+    // finished are returned first. Only the stages in the table are reaped:
+    // other children, like those of process substitutions, are left for
+    // whoever waits for them.
+    pub fn wait_any(&mut self) -> Option<(usize, i32)> {
+        loop {
+            self.poll();
+            let done = self
+                .jobs
+                .iter()
+                .position(|job| job.statuses.iter().all(|status| status.is_some()));
+            if let Some(index) = done {
+                let job = self.jobs.remove(index);
+                return Some((job.id, job.statuses.last().copied().flatten().unwrap_or(0)));
+            }
+
+            if self.jobs.is_empty() {
+                return None;
+            }
+
+            // Sleeps until some child can be waited for, without reaping it.
+            // When it isn't one of ours it stays waitable, so the next look
+            // is delayed rather than spinning on it.
+            let mut info: siginfo_t = unsafe { std::mem::zeroed() };
+            if unsafe { waitid(P_ALL, 0, &mut info, WEXITED | WNOWAIT) } == -1 {
+                if std::io::Error::last_os_error().raw_os_error() != Some(EINTR) {
+                    return None;
+                }
+                continue;
+            }
+            let pid = unsafe { info.si_pid() };
+            if !self.jobs.iter().any(|job| job.pids.contains(&pid)) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    pub fn find_pid(&self, pid: pid_t) -> Option<usize> {
+        self.jobs
+            .iter()
+            .find(|job| job.pids.contains(&pid))
+            .map(|job| job.id)
+    }
+
+    pub fn ids(&self) -> Vec<usize> {
+        self.jobs.iter().map(|job| job.id).collect()
+    }
+
+    // Reaps the stages that exited, without blocking.
+    fn poll(&mut self) {
+        for job in self.jobs.iter_mut() {
+            for (i, &pid) in job.pids.iter().enumerate() {
+                if job.statuses[i].is_some() {
+                    continue;
+                }
+
+                let mut raw = 0;
+                if waitpid(pid, &mut raw, WNOHANG) == pid {
+                    job.statuses[i] = Some(exit_status(raw));
+                }
+            }
+        }
+    }
+
+    // Reaps stages that exited without blocking, returning the ids and
+    // statuses of the jobs that are now complete.
+    pub fn reap(&mut self) -> Vec<(usize, i32)> {
+        self.poll();
+
+        let mut done = vec![];
+        for job in &self.jobs {
+            if job.statuses.iter().all(|status| status.is_some()) {
+                done.push((job.id, job.statuses.last().unwrap().unwrap()));
+            }
+        }
+
+        self.jobs
+            .retain(|job| !done.iter().any(|&(id, _)| id == job.id));
+        done
+    }
+}
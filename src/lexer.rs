@@ -1,24 +1,73 @@
-use crate::command::RedirectOperator;
+use crate::command::{HereDoc, RedirectOperator};
+use crate::expand::{find_matching, is_name};
+
+use std::fmt;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
-    Word(String),
-    SingleQuoted(String),               // 'text'
-    DoubleQuoted(String),               // "text"
+    Word(String),                       // Raw text, quotes are kept for expansion
     Semicolon,                          // ;
+    CaseBreak,                          // ;;
+    CaseFallthrough,                    // ;&
+    CaseContinue,                       // ;;&
     Pipe,                               // |
+    PipeStderr,                         // |&, a pipe taking stderr as well
     And,                                // &&
     Or,                                 // ||
     Background,                         // &
     RedirectOperator(RedirectOperator), // >, >>, >&, <, <<, <&
     LParen,                             // (
     RParen,                             // )
+    HereDoc(HereDoc),                   // Body following `<<DELIM`
+    IoNumber(u32),                      // `2` in `2>file`
+    Newline,                            // Separates commands like `;`
     EOF,                                // End of input
 }
 
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::Semicolon => write!(f, ";"),
+            Token::CaseBreak => write!(f, ";;"),
+            Token::CaseFallthrough => write!(f, ";&"),
+            Token::CaseContinue => write!(f, ";;&"),
+            Token::Pipe => write!(f, "|"),
+            Token::PipeStderr => write!(f, "|&"),
+            Token::And => write!(f, "&&"),
+            Token::Or => write!(f, "||"),
+            Token::Background => write!(f, "&"),
+            Token::RedirectOperator(operator) => write!(f, "{}", operator),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::HereDoc(_) => write!(f, "<<"),
+            Token::IoNumber(fd) => write!(f, "{}", fd),
+            Token::Newline => write!(f, "newline"),
+            Token::EOF => write!(f, "newline"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexError {
+    Incomplete, // Input ended inside quotes, an expansion or a here-document
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LexError::Incomplete => write!(f, "unexpected end of file"),
+        }
+    }
+}
+
 pub struct Lexer {
     input: Vec<char>,
     position: usize,
+    pending_heredoc: Option<bool>, // Set by `<<`, true for `<<-`
+    heredoc_end: Option<usize>,    // End of the bodies read for the current line
+    incomplete: bool,
+    extglob: bool, // Whether `@(...)` and friends are read as part of words
 }
 
 impl Lexer {
@@ -26,9 +75,21 @@ impl Lexer {
         Lexer {
             input: input.chars().collect(),
             position: 0,
+            pending_heredoc: None,
+            heredoc_end: None,
+            incomplete: false,
+            extglob: false,
         }
     }
 
+    pub fn set_extglob(&mut self, enabled: bool) {
+        self.extglob = enabled;
+    }
+
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete
+    }
+
     pub fn tokens(&mut self) -> Vec<Token> {
         let mut tokens = Vec::new();
 
@@ -44,12 +105,39 @@ impl Lexer {
         tokens
     }
 
+    // Skips blanks, line continuations and comments, which start with a `#`
+    // at the beginning of a word and run to the end of the line.
     fn skip_whitespace(&mut self) {
-        while self.position < self.input.len() && self.input[self.position].is_whitespace() {
+        while self.position < self.input.len() {
+            match self.input[self.position] {
+                '\\' if self.input.get(self.position + 1) == Some(&'\n') => {
+                    self.position += 2;
+                    continue;
+                }
+                '#' => {
+                    while self.position < self.input.len() && self.input[self.position] != '\n' {
+                        self.position += 1;
+                    }
+                    continue;
+                }
+                '\n' => break,
+                c if !is_blank(c) => break,
+                _ => {}
+            }
             self.position += 1;
         }
     }
 
+    // A newline ends the command line, so the here-document bodies that
+    // follow it are skipped.
+    fn handle_newline(&mut self) -> Token {
+        self.consume();
+        if let Some(end) = self.heredoc_end.take() {
+            self.position = end;
+        }
+        Token::Newline
+    }
+
     fn peek(&self) -> Option<&char> {
         self.input.get(self.position)
     }
@@ -60,38 +148,64 @@ impl Lexer {
 
     pub fn next_token(&mut self) -> Token {
         self.skip_whitespace();
+        if let Some(strip_tabs) = self.pending_heredoc.take() {
+            return self.read_heredoc(strip_tabs);
+        }
+
         if self.position >= self.input.len() {
             return Token::EOF;
         }
 
         let c = self.peek();
         match c {
+            Some(&'\n') => self.handle_newline(),
             Some(&';') => self.handle_semicolon(),
             Some(&'|') => self.handle_pipe(),
             Some(&'&') => self.handle_ampersand(),
+            Some(&'<') | Some(&'>') if self.input.get(self.position + 1) == Some(&'(') => {
+                self.read_word()
+            }
             Some(&'>') => self.handle_redirect_out(),
             Some(&'<') => self.handle_redirect_in(),
             Some(&'(') => self.handle_parentheses(),
             Some(&')') => self.handle_parentheses(),
-            Some(&'\'') => self.read_single_quoted(),
-            Some(&'"') => self.read_double_quoted(),
             Some(_) => self.read_word(),
-            _ => panic!("Wrong command"),
+            None => Token::EOF,
         }
     }
 
+    // `;` and the terminators of `case` clauses, `;;`, `;&` and `;;&`.
     fn handle_semicolon(&mut self) -> Token {
         self.consume();
-        Token::Semicolon
+        match self.peek() {
+            Some(';') => {
+                self.consume();
+                if self.peek() == Some(&'&') {
+                    self.consume();
+                    return Token::CaseContinue;
+                }
+                Token::CaseBreak
+            }
+            Some('&') => {
+                self.consume();
+                Token::CaseFallthrough
+            }
+            _ => Token::Semicolon,
+        }
     }
 
     fn handle_pipe(&mut self) -> Token {
         self.consume();
-        if self.peek() == Some(&'|') {
-            self.consume();
-            Token::Or
-        } else {
-            Token::Pipe
+        match self.peek() {
+            Some('|') => {
+                self.consume();
+                Token::Or
+            }
+            Some('&') => {
+                self.consume();
+                Token::PipeStderr
+            }
+            _ => Token::Pipe,
         }
     }
 
@@ -105,11 +219,17 @@ impl Lexer {
         }
     }
 
+    // `<`, `<<`, `<<-` and `<&`, told apart by the character after `<`.
     fn handle_redirect_in(&mut self) -> Token {
         self.consume();
         match self.peek() {
             Some('<') => {
                 self.consume();
+                let strip_tabs = self.peek() == Some(&'-');
+                if strip_tabs {
+                    self.consume();
+                }
+                self.pending_heredoc = Some(strip_tabs);
                 Token::RedirectOperator(RedirectOperator::HereDoc)
             }
             Some('&') => {
@@ -120,10 +240,11 @@ impl Lexer {
         }
     }
 
+    // `>`, `>>` and `>&`, told apart by the character after `>`.
     fn handle_redirect_out(&mut self) -> Token {
         self.consume();
         match self.peek() {
-            Some('<') => {
+            Some('>') => {
                 self.consume();
                 Token::RedirectOperator(RedirectOperator::Append)
             }
@@ -145,16 +266,6 @@ impl Lexer {
         }
     }
 
-    fn read_single_quoted(&mut self) -> Token {
-        let content = self.read_quoted('\'');
-        Token::SingleQuoted(content)
-    }
-
-    fn read_double_quoted(&mut self) -> Token {
-        let content = self.read_quoted('"');
-        Token::DoubleQuoted(content)
-    }
-
     fn read_quoted(&mut self, quote: char) -> String {
         let mut content = String::new();
         self.consume();
@@ -177,23 +288,463 @@ impl Lexer {
         content
     }
 
+    fn read_heredoc(&mut self, strip_tabs: bool) -> Token {
+        let mut delimiter = String::new();
+        let mut quoted = false;
+
+        while let Some(&c) = self.peek() {
+            if is_blank(c) || c == '\n' || self.is_operator(c) {
+                break;
+            }
+
+            match c {
+                '\'' | '"' => {
+                    quoted = true;
+                    delimiter.push_str(&self.read_quoted(c));
+                }
+                '\\' => {
+                    quoted = true;
+                    self.consume();
+                    if let Some(&c) = self.peek() {
+                        delimiter.push(c);
+                        self.consume();
+                    }
+                }
+                _ => {
+                    delimiter.push(c);
+                    self.consume();
+                }
+            }
+        }
+
+        // Bodies follow the command line in the order their operators
+        // appeared, so each one starts where the previous one ended.
+        let start = match self.heredoc_end {
+            Some(end) => end,
+            None => match self.input[self.position..].iter().position(|&c| c == '\n') {
+                Some(offset) => self.position + offset + 1,
+                None => self.input.len(),
+            },
+        };
+
+        let mut body = String::new();
+        let mut cursor = start;
+        let mut terminated = false;
+
+        while cursor < self.input.len() {
+            let end = self.input[cursor..]
+                .iter()
+                .position(|&c| c == '\n')
+                .map(|offset| cursor + offset)
+                .unwrap_or(self.input.len());
+
+            let mut line: String = self.input[cursor..end].iter().collect();
+            if strip_tabs {
+                line = line.trim_start_matches('\t').to_string();
+            }
+
+            cursor = (end + 1).min(self.input.len());
+            if line == delimiter {
+                terminated = true;
+                break;
+            }
+
+            body.push_str(&line);
+            body.push('\n');
+        }
+
+        if !terminated {
+            self.incomplete = true;
+        }
+
+        self.heredoc_end = Some(cursor);
+        Token::HereDoc(HereDoc { body, quoted })
+    }
+
     fn read_word(&mut self) -> Token {
         let mut word = String::new();
 
         while self.position < self.input.len() {
             let c = self.input[self.position];
-            if c.is_whitespace() || self.is_operator(c) {
+
+            // The elements of `NAME=(...)` belong to the assignment word.
+            let name = word
+                .strip_suffix('=')
+                .map(|w| w.strip_suffix('+').unwrap_or(w));
+            if c == '(' && name.is_some_and(is_name) {
+                self.read_raw_group(&mut word);
+                continue;
+            }
+
+            // A process substitution, `<(...)` or `>(...)`, is a word.
+            if matches!(c, '<' | '>')
+                && word.is_empty()
+                && self.input.get(self.position + 1) == Some(&'(')
+            {
+                word.push(c);
+                self.consume();
+                self.read_raw_group(&mut word);
+                continue;
+            }
+
+            if is_blank(c) || c == '\n' || self.is_operator(c) {
                 break;
             }
 
+            match c {
+                '\'' | '"' | '`' => self.read_raw_quoted(c, &mut word),
+                '\\' => {
+                    self.consume();
+                    match self.peek() {
+                        Some('\n') => self.consume(), // Line continuation
+                        Some(&c) => {
+                            word.push('\\');
+                            word.push(c);
+                            self.consume();
+                        }
+                        None => self.incomplete = true,
+                    }
+                }
+                '$' if self.input.get(self.position + 1) == Some(&'\'') => {
+                    word.push('$');
+                    self.consume();
+                    self.read_raw_quoted('\'', &mut word);
+                }
+                '$' if matches!(self.input.get(self.position + 1), Some('(') | Some('{')) => {
+                    self.read_raw_expansion(&mut word)
+                }
+                '?' | '*' | '+' | '@' | '!'
+                    if self.input.get(self.position + 1) == Some(&'(') && self.extglob =>
+                {
+                    word.push(c);
+                    self.consume();
+                    self.read_raw_group(&mut word);
+                }
+                _ => {
+                    word.push(c);
+                    self.consume();
+                }
+            }
+        }
+
+        if !word.is_empty()
+            && word.chars().all(|c| c.is_ascii_digit())
+            && matches!(self.peek(), Some('<') | Some('>'))
+        {
+            if let Ok(fd) = word.parse::<u32>() {
+                return Token::IoNumber(fd);
+            }
+        }
+
+        Token::Word(word)
+    }
+
+    // Copies a quoted section verbatim, leaving quote removal to expansion.
+    // Backslashes escape the quote except in single quotes, unless they
+    // are the `$'...'` kind.
+    fn read_raw_quoted(&mut self, quote: char, word: &mut String) {
+        let ansi_c = word.ends_with('$') && quote == '\'';
+        word.push(quote);
+        self.consume();
+
+        while let Some(&c) = self.peek() {
+            if quote == '"'
+                && c == '$'
+                && matches!(self.input.get(self.position + 1), Some('(') | Some('{'))
+            {
+                self.read_raw_expansion(word);
+                continue;
+            }
             word.push(c);
             self.consume();
+
+            if c == quote {
+                return;
+            } else if c == '\\' && (quote != '\'' || ansi_c) {
+                if let Some(&c) = self.peek() {
+                    word.push(c);
+                    self.consume();
+                }
+            }
         }
 
-        Token::Word(word)
+        self.incomplete = true;
+    }
+
+    // Copies a `$(...)` or `${...}` expansion verbatim, including any
+    // operators, whitespace, quotes or nested expansions it contains.
+    fn read_raw_expansion(&mut self, word: &mut String) {
+        let close = match self.input.get(self.position + 1) {
+            Some('(') => ')',
+            _ => '}',
+        };
+
+        let end = find_matching(&self.input, self.position + 2, close);
+        let stop = end.map_or(self.input.len(), |end| end + 1);
+        word.extend(&self.input[self.position..stop]);
+        self.position = stop;
+        if end.is_none() {
+            self.incomplete = true;
+        }
+    }
+
+    // Copies an extended glob group such as `(a|b)` verbatim.
+    fn read_raw_group(&mut self, word: &mut String) {
+        let mut depth = 0;
+        while let Some(&c) = self.peek() {
+            word.push(c);
+            self.consume();
+
+            match c {
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return;
+                    }
+                }
+                '\\' => {
+                    if let Some(&c) = self.peek() {
+                        word.push(c);
+                        self.consume();
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        self.incomplete = true;
     }
 
     fn is_operator(&self, c: char) -> bool {
         matches!(c, ';' | '|' | '&' | '>' | '<' | '(' | ')')
     }
 }
+
+// Only spaces and tabs separate words, whatever the locale considers
+// whitespace, so that a non-breaking space stays inside its word.
+fn is_blank(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{ParseError, Parser};
+    use crate::shell::Shell;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        Lexer::new(input.to_string()).tokens()
+    }
+
+    fn words(words: &[&str]) -> Vec<Token> {
+        words
+            .iter()
+            .map(|word| Token::Word(word.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn comment_after_command() {
+        assert_eq!(tokens("ls -l # list files"), words(&["ls", "-l"]));
+        assert_eq!(tokens("# only a comment"), vec![]);
+        assert_eq!(
+            tokens("echo a;# no space"),
+            [words(&["echo", "a"]), vec![Token::Semicolon]].concat()
+        );
+    }
+
+    #[test]
+    fn comment_after_operator() {
+        assert_eq!(
+            tokens("echo a && # note\necho b"),
+            [
+                words(&["echo", "a"]),
+                vec![Token::And, Token::Newline],
+                words(&["echo", "b"]),
+            ]
+            .concat()
+        );
+        assert_eq!(
+            tokens("echo a | # note\ncat"),
+            [
+                words(&["echo", "a"]),
+                vec![Token::Pipe, Token::Newline],
+                words(&["cat"]),
+            ]
+            .concat()
+        );
+
+        // The interactive shell reads another line for what follows.
+        let mut parser = Parser::new(Lexer::new("echo a && # note".to_string()));
+        assert_eq!(parser.parse().err(), Some(ParseError::Incomplete));
+    }
+
+    #[test]
+    fn hash_inside_words() {
+        assert_eq!(
+            tokens(r#"echo "a # b" 'c # d' e\#f g#h"#),
+            words(&["echo", r#""a # b""#, "'c # d'", r"e\#f", "g#h"])
+        );
+        assert_eq!(tokens("echo $# ${#x}"), words(&["echo", "$#", "${#x}"]));
+    }
+
+    #[test]
+    fn comments_are_not_run() {
+        let mut shell = Shell::new();
+        shell.eval("x='a # b' # x=c");
+        assert_eq!(shell.variables.get("x"), Some("a # b"));
+        shell.eval("y=1 && # y=2\ny=3");
+        assert_eq!(shell.variables.get("y"), Some("3"));
+    }
+
+    #[test]
+    fn redirect_operators() {
+        use RedirectOperator::*;
+
+        for (input, operator) in [
+            ("cmd > file", Overwrite),
+            ("cmd >> file", Append),
+            ("cmd >& file", DuplicateOut),
+            ("cmd < file", Input),
+            ("cmd <& file", DuplicateIn),
+            ("cmd>file", Overwrite),
+            ("cmd>>file", Append),
+            ("cmd<file", Input),
+        ] {
+            let expected = [
+                words(&["cmd"]),
+                vec![Token::RedirectOperator(operator)],
+                words(&["file"]),
+            ]
+            .concat();
+            assert_eq!(tokens(input), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn pipe_operators() {
+        for (input, operator) in [
+            ("a | b", vec![Token::Pipe]),
+            ("a|b", vec![Token::Pipe]),
+            ("a |& b", vec![Token::PipeStderr]),
+            ("a|&b", vec![Token::PipeStderr]),
+            ("a || b", vec![Token::Or]),
+            ("a | & b", vec![Token::Pipe, Token::Background]),
+        ] {
+            let expected = [words(&["a"]), operator, words(&["b"])].concat();
+            assert_eq!(tokens(input), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn redirect_io_numbers() {
+        assert_eq!(
+            tokens("cmd 2>&1"),
+            [
+                words(&["cmd"]),
+                vec![
+                    Token::IoNumber(2),
+                    Token::RedirectOperator(RedirectOperator::DuplicateOut),
+                ],
+                words(&["1"]),
+            ]
+            .concat()
+        );
+        assert_eq!(
+            tokens("cmd 0<in 12>>out"),
+            [
+                words(&["cmd"]),
+                vec![
+                    Token::IoNumber(0),
+                    Token::RedirectOperator(RedirectOperator::Input),
+                ],
+                words(&["in"]),
+                vec![
+                    Token::IoNumber(12),
+                    Token::RedirectOperator(RedirectOperator::Append),
+                ],
+                words(&["out"]),
+            ]
+            .concat()
+        );
+        // Digits followed by a blank are an ordinary argument.
+        assert_eq!(
+            tokens("cmd 2 > file"),
+            [
+                words(&["cmd", "2"]),
+                vec![Token::RedirectOperator(RedirectOperator::Overwrite)],
+                words(&["file"]),
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn redirect_heredoc() {
+        let heredoc = |quoted| {
+            [
+                words(&["cat"]),
+                vec![
+                    Token::RedirectOperator(RedirectOperator::HereDoc),
+                    Token::HereDoc(HereDoc {
+                        body: "hello\n".to_string(),
+                        quoted,
+                    }),
+                    Token::Newline,
+                ],
+            ]
+            .concat()
+        };
+        assert_eq!(tokens("cat <<EOF\nhello\nEOF\n"), heredoc(false));
+        assert_eq!(tokens("cat<<EOF\nhello\nEOF\n"), heredoc(false));
+        assert_eq!(tokens("cat <<-'EOF'\n\thello\n\tEOF\n"), heredoc(true));
+        assert_eq!(tokens("cat <<-\"EOF\"\n\thello\n\tEOF\n"), heredoc(true));
+        assert_eq!(tokens("cat <<E\\OF\nhello\nEOF\n"), heredoc(true));
+        assert_eq!(tokens("cat <<E\"O\"F\nhello\nEOF\n"), heredoc(true));
+    }
+
+    #[test]
+    fn only_spaces_and_tabs_separate_words() {
+        assert_eq!(
+            tokens("echo a\u{a0}b\tc \u{a0}"),
+            words(&["echo", "a\u{a0}b", "c", "\u{a0}"])
+        );
+        assert_eq!(tokens("x=\u{2003}1"), words(&["x=\u{2003}1"]));
+    }
+
+    #[test]
+    fn quotes_stay_in_one_word() {
+        assert_eq!(
+            tokens(r#"echo 'a b' "c d" e\ f 'g'"h i"j\ k"#),
+            words(&["echo", "'a b'", r#""c d""#, r"e\ f", r#"'g'"h i"j\ k"#])
+        );
+        assert_eq!(
+            tokens(r#"echo "a 'b' c" 'd "e" f' "g\"h""#),
+            words(&["echo", r#""a 'b' c""#, r#"'d "e" f'"#, r#""g\"h""#])
+        );
+    }
+
+    #[test]
+    fn heredocs_in_order() {
+        let heredoc = |body: &str| {
+            Token::HereDoc(HereDoc {
+                body: body.to_string(),
+                quoted: false,
+            })
+        };
+        let expected = [
+            words(&["cat"]),
+            vec![
+                Token::RedirectOperator(RedirectOperator::HereDoc),
+                heredoc("one\n"),
+                Token::IoNumber(3),
+                Token::RedirectOperator(RedirectOperator::HereDoc),
+                heredoc("two\n"),
+                Token::Newline,
+            ],
+        ]
+        .concat();
+        assert_eq!(tokens("cat <<A 3<<B\none\nA\ntwo\nB\n"), expected);
+    }
+}
@@ -1,5 +1,82 @@
+pub mod arithmetic;
 pub mod command;
+pub mod complete;
+pub mod expand;
+pub mod glob;
 pub mod input;
+pub mod jobs;
 pub mod lexer;
+pub mod options;
 pub mod parser;
 pub mod prompt;
+pub mod shell;
+pub mod signals;
+pub mod times;
+pub mod variables;
+
+use command::Command;
+use lexer::{LexError, Lexer, Token};
+use parser::{ParseError, Parser};
+use shell::Shell;
+
+/// Lexes, parses and executes `input` in a fresh shell, returning its exit
+/// status.
+///
+/// ```
+/// assert_eq!(rush::run("x=2; test $x = 2"), 0);
+/// assert_eq!(rush::run("true && false"), 1);
+/// ```
+pub fn run(input: &str) -> i32 {
+    Shell::new().eval(input)
+}
+
+/// Splits `input` into the tokens the parser reads, without the final
+/// `Token::EOF`. Words are kept as written, quotes included.
+///
+/// ```
+/// use rush::lexer::{LexError, Token};
+///
+/// let tokens = rush::tokenize("a | 'b c'").unwrap();
+/// assert_eq!(
+///     tokens,
+///     [
+///         Token::Word("a".to_string()),
+///         Token::Pipe,
+///         Token::Word("'b c'".to_string()),
+///     ]
+/// );
+/// assert_eq!(rush::tokenize("echo 'a"), Err(LexError::Incomplete));
+/// ```
+pub fn tokenize(input: &str) -> Result<Vec<Token>, LexError> {
+    let mut lexer = Lexer::new(input.to_string());
+    let tokens = lexer.tokens();
+    match lexer.is_incomplete() {
+        true => Err(LexError::Incomplete),
+        false => Ok(tokens),
+    }
+}
+
+/// Parses `input` into a command without running it, as a shell without
+/// aliases and with the default options would.
+///
+/// ```
+/// use rush::command::{Command, Operator};
+/// use rush::parser::ParseError;
+///
+/// // `&&` binds looser than `|`, so the pipeline is its left side.
+/// let Ok(Command::Binary { left, operator, .. }) = rush::parse("a | b && c") else {
+///     panic!("expected a binary command");
+/// };
+/// assert_eq!(operator, Operator::And);
+/// assert!(matches!(
+///     *left,
+///     Command::Binary {
+///         operator: Operator::Pipe,
+///         ..
+///     }
+/// ));
+/// assert_eq!(rush::parse("a &&").err(), Some(ParseError::Incomplete));
+/// ```
+pub fn parse(input: &str) -> Result<Command, ParseError> {
+    Parser::new(Lexer::new(input.to_string())).parse()
+}
@@ -1,9 +1,12 @@
-use rush::input::input_read;
-use rush::lexer::Lexer;
-use rush::parser::Parser;
+use rush::command::error_message;
+use rush::complete;
+use rush::input::{input_history, input_read};
+use rush::parser::ParseError;
 use rush::prompt::prompt;
+use rush::shell::Shell;
+use rush::signals;
 
-use std::ffi::CString;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use libc::c_int;
 use libc::{exit, getpid, getsid, setsid, signal, write};
@@ -11,58 +14,268 @@ use libc::{SIGINT, SIGPIPE, SIGQUIT, SIGTTIN, SIGTTOU, SIG_DFL, SIG_IGN, STDOUT_
 
 extern "C" {
     static mut rl_catch_signals: c_int;
+    static mut rl_signal_event_hook: Option<extern "C" fn() -> c_int>;
 
     fn rl_on_new_line();
     fn rl_replace_line(text: *const i8, clear_undo: c_int);
     fn rl_redisplay();
 }
 
+// Set while readline is waiting for input. At any other time a foreground
+// command owns the terminal and receives the interrupt itself.
+static AT_PROMPT: AtomicBool = AtomicBool::new(false);
+
+// Set by the SIGINT handler for the line being edited to be discarded.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Only async-signal-safe work happens here: readline is told about the
+// interrupt through `INTERRUPTED` once its read returns with EINTR.
 extern "C" fn sigint_handler(_signum: c_int) {
-    unsafe {
-        write(STDOUT_FILENO, "\n".as_ptr() as *const _, 1);
+    if !AT_PROMPT.load(Ordering::SeqCst) {
+        return;
+    }
+
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    unsafe { write(STDOUT_FILENO, "\n".as_ptr() as *const _, 1) };
+}
+
+// Called by readline outside of the signal handler after a signal
+// interrupts its read, to start over on a fresh line.
+extern "C" fn signal_event_hook() -> c_int {
+    if INTERRUPTED.swap(false, Ordering::SeqCst) {
+        unsafe {
+            rl_on_new_line();
+            rl_replace_line(c"".as_ptr(), 0);
+            rl_redisplay();
+        }
+    }
+    0
+}
+
+fn read_line(prompt: String) -> Option<String> {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    AT_PROMPT.store(true, Ordering::SeqCst);
+    let line = input_read(prompt);
+    AT_PROMPT.store(false, Ordering::SeqCst);
+    line
+}
+
+const USAGE: &str = "\
+Usage: rush [option] [script [argument ...]]
+
+Without a script, commands are read interactively from the terminal.
 
-        let s = CString::new("").unwrap();
-        rl_on_new_line();
-        rl_replace_line(s.as_ptr(), 0);
-        rl_redisplay();
+Options:
+  -c command [name [argument ...]]
+                 Run the command string, with $0 set to name and the
+                 arguments as positional parameters
+  -l, --login    Act as a login shell and read ~/.rush_profile
+  -n             Read and check commands without running them
+  --noprofile    Do not read ~/.rush_profile
+  --norc         Do not read ~/.rushrc in an interactive shell
+  --help         Print this help and exit
+  --version      Print the version and exit
+";
+
+// Runs a startup file from the home directory if there is one. Errors in
+// it are reported like in any script but never keep the shell from
+// starting.
+fn load_startup_file(name: &str, shell: &mut Shell) {
+    let Some(home) = shell.variables.get("HOME") else {
+        return;
+    };
+
+    let path = format!("{}/{}", home, name);
+    match std::fs::read_to_string(&path) {
+        Ok(script) => {
+            let line = shell.variables.line();
+            shell.eval_script(&script);
+            shell.variables.set_line(line);
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => eprintln!("rush: {}: {}", path, error_message(&e)),
     }
 }
 
 fn main() {
+    // Commands must not inherit the SIGPIPE disposition Rust sets up.
+    unsafe { signal(SIGPIPE, SIG_DFL) };
+
+    let mut args: Vec<String> = std::env::args().collect();
+    let mut shell = Shell::new();
+    increment_shlvl(&mut shell);
+
+    // Startup options come first and are removed so that the rest of the
+    // arguments are handled the same way with or without them.
+    let mut login = args[0].starts_with('-');
+    let mut profile = true;
+    let mut rc = true;
+    while let Some(arg) = args.get(1) {
+        match arg.as_str() {
+            "-l" | "--login" => login = true,
+            "--noprofile" => profile = false,
+            "--norc" => rc = false,
+            "-n" => {
+                let _ = shell.options.set_set_option("noexec", true);
+            }
+            _ => break,
+        }
+        args.remove(1);
+    }
+
+    if login && profile {
+        load_startup_file(".rush_profile", &mut shell);
+    }
+
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("--version") => println!("rush {}", env!("CARGO_PKG_VERSION")),
+        Some("--help") => print!("{}", USAGE),
+        Some("-c") => {
+            let command = match args.get(2) {
+                Some(command) => command,
+                None => {
+                    eprintln!("rush: -c: option requires an argument");
+                    std::process::exit(2);
+                }
+            };
+
+            if let Some(name) = args.get(3) {
+                shell.name = name.clone();
+                shell.positional = args[4..].to_vec();
+            }
+            std::process::exit(shell.eval_script(command));
+        }
+        Some(option) if option.starts_with('-') => {
+            eprintln!("rush: {}: invalid option", option);
+            eprint!("{}", USAGE);
+            std::process::exit(2);
+        }
+        Some(path) => {
+            let script = match std::fs::read_to_string(path) {
+                Ok(script) => script,
+                Err(e) => {
+                    eprintln!("rush: {}: {}", path, error_message(&e));
+                    std::process::exit(127);
+                }
+            };
+
+            shell.name = path.to_string();
+            shell.positional = args[2..].to_vec();
+            std::process::exit(shell.eval_script(&script));
+        }
+        None => {
+            if rc {
+                load_startup_file(".rushrc", &mut shell);
+            }
+            interactive(shell)
+        }
+    }
+}
+
+// Counts this shell in `$SHLVL`, which is exported so that the shells it
+// starts count from it. A missing or non-numeric value counts as 0.
+fn increment_shlvl(shell: &mut Shell) {
+    let level: i64 = shell
+        .variables
+        .get("SHLVL")
+        .and_then(|level| level.trim().parse().ok())
+        .unwrap_or(0);
+    let _ = shell.variables.set("SHLVL", &(level + 1).to_string());
+    shell.variables.export("SHLVL");
+}
+
+// How many consecutive ends of input are ignored before the shell exits:
+// the value of `$IGNOREEOF`, 10 when it isn't a number or when only
+// `set -o ignoreeof` is used, and none otherwise.
+fn ignored_eofs(shell: &Shell) -> u32 {
+    match shell.variables.get("IGNOREEOF") {
+        Some(count) => count.parse().unwrap_or(10),
+        None if shell.options.enabled("ignoreeof") => 10,
+        None => 0,
+    }
+}
+
+fn continuation_prompt(shell: &Shell) -> String {
+    shell.variables.get("PS2").unwrap_or("> ").to_string()
+}
+
+fn interactive(mut shell: Shell) {
     unsafe {
         if getsid(0) != getpid() {
             setsid();
         }
 
-        signal(SIGTTOU, SIG_IGN);
-        signal(SIGTTIN, SIG_IGN);
-
         rl_catch_signals = 0;
-        signal(SIGINT, sigint_handler as usize);
-        signal(SIGPIPE, SIG_DFL);
-        signal(SIGQUIT, SIG_IGN);
+        rl_signal_event_hook = Some(signal_event_hook);
+        complete::init();
     }
 
-    loop {
-        let input = input_read(prompt());
+    // These are only the shell's own dispositions, which `trap` can change
+    // and which commands don't inherit.
+    signals::set_default(&mut shell, SIGTTOU, SIG_IGN);
+    signals::set_default(&mut shell, SIGTTIN, SIG_IGN);
+    signals::set_default(&mut shell, SIGINT, sigint_handler as *const () as usize);
+    signals::set_default(&mut shell, SIGQUIT, SIG_IGN);
+
+    shell.job_control = true;
+    let mut eofs = 0;
 
-        if input.is_none() {
-            unsafe { exit(0) };
+    loop {
+        for (id, status) in shell.jobs.reap() {
+            match status {
+                0 => eprintln!("[{}]+ Done", id),
+                _ => eprintln!("[{}]+ Exit {}", id, status),
+            }
         }
+        signals::run_pending(&mut shell);
 
-        let input = input.unwrap();
-        if input.trim().is_empty() {
-            continue;
+        // `$?` is kept from the last command typed, not from the hook.
+        if let Some(command) = shell.variables.get("PROMPT_COMMAND").map(String::from) {
+            let status = shell.last_status;
+            shell.eval(&command);
+            shell.last_status = status;
         }
 
-        let lexer = Lexer::new(input);
-        let command = Parser::new(lexer).parse();
+        complete::update(&shell);
+        let input = read_line(prompt(&mut shell));
 
-        match command {
-            Ok(command) => {
-                let _ = command.execute();
+        // Readline only reports the end of input for Ctrl-D on an empty
+        // line, which `$IGNOREEOF` or `set -o ignoreeof` can make take more
+        // than one press to leave the shell.
+        let Some(mut input) = input else {
+            eofs += 1;
+            if eofs > ignored_eofs(&shell) {
+                unsafe { exit(shell.last_status) };
             }
-            Err(e) => eprintln!("Parsing error: {}", e),
+            eprintln!("Use \"exit\" to leave the shell.");
+            continue;
+        };
+        eofs = 0;
+
+        if shell.is_blank(&input) {
+            if !input.trim().is_empty() {
+                input_history(&input);
+            }
+            continue;
         }
+
+        shell.variables.set_line(shell.variables.line() + 1);
+        // Input ending inside a construct or after an operator such as `&&`
+        // or `|` is continued on the next line, read with `$PS2`.
+        let command = loop {
+            match shell.parse(&input) {
+                Err(ParseError::Incomplete) => match read_line(continuation_prompt(&shell)) {
+                    Some(line) => {
+                        input.push('\n');
+                        input.push_str(&line);
+                    }
+                    None => break Err(ParseError::Incomplete),
+                },
+                result => break result,
+            }
+        };
+        input_history(&input);
+
+        shell.run(command);
     }
 }
@@ -0,0 +1,81 @@
+// Options toggled with `shopt` and with `set -o`, each listed in the order
+// the builtin prints them.
+#[derive(Clone)]
+pub struct Options {
+    shopt: [(&'static str, bool); 5],
+    set: [(&'static str, bool); 3],
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            shopt: [
+                ("extglob", false),
+                ("failglob", false),
+                ("nocaseglob", false),
+                ("nullglob", false),
+                ("xpg_echo", false),
+            ],
+            set: [("ignoreeof", false), ("noexec", false), ("pipefail", false)],
+        }
+    }
+}
+
+impl Options {
+    pub fn shopt(&self, name: &str) -> Option<bool> {
+        self.shopt
+            .iter()
+            .find(|(option, _)| *option == name)
+            .map(|&(_, enabled)| enabled)
+    }
+
+    // Whether the option is set, unknown options counting as unset.
+    pub fn enabled(&self, name: &str) -> bool {
+        self.shopt(name)
+            .or_else(|| self.set_option(name))
+            .unwrap_or(false)
+    }
+
+    pub fn set_option(&self, name: &str) -> Option<bool> {
+        self.set
+            .iter()
+            .find(|(option, _)| *option == name)
+            .map(|&(_, enabled)| enabled)
+    }
+
+    pub fn set_set_option(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        match self.set.iter_mut().find(|(option, _)| *option == name) {
+            Some(option) => {
+                option.1 = enabled;
+                Ok(())
+            }
+            None => Err(format!("{}: invalid option name", name)),
+        }
+    }
+
+    // The `set -o` option a single letter like the `n` of `set -n` stands for.
+    pub fn short_name(flag: char) -> Option<&'static str> {
+        match flag {
+            'n' => Some("noexec"),
+            _ => None,
+        }
+    }
+
+    pub fn set_options(&self) -> Vec<(&'static str, bool)> {
+        self.set.to_vec()
+    }
+
+    pub fn set_shopt(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        match self.shopt.iter_mut().find(|(option, _)| *option == name) {
+            Some(option) => {
+                option.1 = enabled;
+                Ok(())
+            }
+            None => Err(format!("{}: invalid shell option name", name)),
+        }
+    }
+
+    pub fn shopt_options(&self) -> Vec<(&'static str, bool)> {
+        self.shopt.to_vec()
+    }
+}
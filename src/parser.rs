@@ -1,9 +1,39 @@
-use crate::command::{Command, Operator, RedirectOperator, RedirectTarget, Redirection};
+use crate::command::{
+    CaseClause, CaseTerminator, Command, Operator, RedirectOperator, RedirectTarget, Redirection,
+};
+use crate::expand::{is_assignment, is_name};
 use crate::lexer::{Lexer, Token};
 
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Syntax(String),
+    UnexpectedToken(Token),
+    Incomplete, // Input ended before a construct was terminated
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Syntax(message) => write!(f, "syntax error: {}", message),
+            ParseError::UnexpectedToken(token) => {
+                write!(f, "syntax error near unexpected token '{}'", token)
+            }
+            ParseError::Incomplete => write!(f, "syntax error: unexpected end of file"),
+        }
+    }
+}
+
+// Reserved words that end a list, which can't start a command.
+const CLOSING: [&str; 8] = ["}", "do", "done", "elif", "else", "esac", "fi", "then"];
+
 pub struct Parser {
     lexer: Lexer,
     current_token: Token,
+    aliases: HashMap<String, String>,
+    pending: VecDeque<Token>, // Tokens of an alias value, read before the lexer's
 }
 
 impl Parser {
@@ -13,44 +43,145 @@ impl Parser {
         Parser {
             lexer,
             current_token,
+            aliases: HashMap::new(),
+            pending: VecDeque::new(),
         }
     }
 
-    pub fn parse(&mut self) -> Result<Command, String> {
-        self.parse_with_min_precedence(0)
+    pub fn set_aliases(&mut self, aliases: HashMap<String, String>) {
+        self.aliases = aliases;
+    }
+
+    pub fn parse(&mut self) -> Result<Command, ParseError> {
+        self.skip_newlines();
+        let command =
+            self.parse_with_min_precedence(0)
+                .and_then(|command| match self.current_token {
+                    Token::EOF => Ok(command),
+                    _ => Err(self.unexpected()),
+                });
+
+        if self.lexer.is_incomplete() {
+            return Err(ParseError::Incomplete);
+        }
+
+        command
     }
 
-    fn expect(&mut self, expected: Token) -> Result<(), String> {
+    fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
         if self.current_token == expected {
             self.advance();
             Ok(())
         } else {
-            Err(format!(
-                "Expected {:?}, got {:?}",
-                expected, self.current_token
-            ))
+            Err(self.unexpected())
+        }
+    }
+
+    // Running out of input is reported separately, so that interactive
+    // callers can ask for a continuation line instead of failing.
+    fn unexpected(&self) -> ParseError {
+        match self.current_token {
+            Token::EOF => ParseError::Incomplete,
+            _ => ParseError::UnexpectedToken(self.current_token.clone()),
         }
     }
 
     fn advance(&mut self) {
-        self.current_token = self.lexer.next_token();
+        self.current_token = match self.pending.pop_front() {
+            Some(token) => token,
+            None => self.lexer.next_token(),
+        };
+    }
+
+    // The token after the current one, read ahead without consuming it.
+    fn peek(&mut self) -> &Token {
+        if self.pending.is_empty() {
+            let token = self.lexer.next_token();
+            self.pending.push_back(token);
+        }
+        &self.pending[0]
+    }
+
+    // Replaces an alias in command position by the tokens of its value.
+    // This is purely textual: the value is lexed like typed input and its
+    // words are only expanded when the command runs, so `alias l='ls $HOME'`
+    // uses `$HOME` as it is then. An alias whose value starts with itself,
+    // directly or not, is only replaced once.
+    fn expand_alias(&mut self) {
+        let mut expanded = vec![];
+        while let Token::Word(word) = &self.current_token {
+            let value = match self.aliases.get(word) {
+                Some(value) if !expanded.contains(word) => value.clone(),
+                _ => break,
+            };
+            expanded.push(word.clone());
+
+            for token in Lexer::new(value).tokens().into_iter().rev() {
+                self.pending.push_front(token);
+            }
+            self.advance();
+        }
     }
 
-    fn parse_with_min_precedence(&mut self, min_precedence: u8) -> Result<Command, String> {
+    // Blank lines are allowed wherever a command list may start or continue.
+    fn skip_newlines(&mut self) {
+        while self.current_token == Token::Newline {
+            self.advance();
+        }
+    }
+
+    fn parse_with_min_precedence(&mut self, min_precedence: u8) -> Result<Command, ParseError> {
         let mut left;
-        if self.current_token == Token::LParen {
-            left = self.parse_group()?;
+        if self.is_reserved("time") {
+            // `time` applies to the whole pipeline that follows it.
+            self.advance();
+            let command = self.parse_with_min_precedence(3)?;
+            left = Command::Timed {
+                command: Box::new(command),
+            };
+        } else if self.is_reserved("coproc") {
+            // Like in bash, only a compound command can be given a name, so
+            // that `coproc cat file` runs `cat` rather than `file`.
+            self.advance();
+            let mut name = "COPROC".to_string();
+            if let Token::Word(word) = self.current_token.clone() {
+                let compound = match self.peek() {
+                    Token::LParen => true,
+                    Token::Word(next) => next == "{",
+                    _ => false,
+                };
+                if compound && is_name(&word) {
+                    name = word;
+                    self.advance();
+                }
+            }
+
+            let command = self.parse_with_min_precedence(4)?;
+            left = Command::Coproc {
+                name,
+                command: Box::new(command),
+            };
+        } else if self.is_reserved("!") {
+            // `!` negates the status of the pipeline that follows it.
+            self.advance();
+            let command = self.parse_with_min_precedence(3)?;
+            left = Command::Not {
+                command: Box::new(command),
+            };
+        } else if let Some(command) = self.parse_compound()? {
+            left = command;
         } else {
             left = self.parse_command()?;
         }
 
+        // Pipelines bind tightest and are complete operands of `&&` and `||`,
+        // which share a precedence and associate to the left like in POSIX.
         loop {
             let (operator, precedence) = match self.current_token {
-                Token::Pipe => (Operator::Pipe, 4),
-                Token::And => (Operator::And, 3),
+                Token::Pipe | Token::PipeStderr => (Operator::Pipe, 3),
+                Token::And => (Operator::And, 2),
                 Token::Or => (Operator::Or, 2),
-                Token::Semicolon => (Operator::Semicolon, 1),
-                Token::Background => (Operator::Background, 1),
+                Token::Semicolon | Token::Newline | Token::Background => (Operator::Semicolon, 1),
                 _ => break,
             };
 
@@ -58,7 +189,23 @@ impl Parser {
                 break;
             }
 
+            // `&` only starts the and-or list just before it as a job, and
+            // separates it from the rest of the list like `;`.
+            if self.current_token == Token::Background {
+                left = background(left);
+            }
+
+            // `|&` is `2>&1 |`, after any redirections of the command.
+            if self.current_token == Token::PipeStderr {
+                left = stderr_to_stdout(left);
+            }
+
             self.advance();
+            self.skip_newlines();
+
+            if operator == Operator::Semicolon && self.at_list_end() {
+                break;
+            }
 
             let right = self.parse_with_min_precedence(precedence + 1)?;
             left = Command::Binary {
@@ -71,9 +218,52 @@ impl Parser {
         Ok(left)
     }
 
-    fn parse_group(&mut self) -> Result<Command, String> {
+    // A compound command, along with the redirections following it, which
+    // apply to the whole command. `None` when no compound command starts at
+    // the current token.
+    fn parse_compound(&mut self) -> Result<Option<Command>, ParseError> {
+        let command = if self.current_token == Token::LParen {
+            self.parse_group()?
+        } else if self.is_reserved("{") {
+            self.parse_brace_group()?
+        } else if self.is_reserved("case") {
+            self.parse_case()?
+        } else if self.is_reserved("select") {
+            self.parse_select()?
+        } else if self.is_reserved("for") {
+            self.parse_for()?
+        } else if self.is_reserved("while") || self.is_reserved("until") {
+            self.parse_while()?
+        } else if self.is_reserved("if") {
+            self.parse_if()?
+        } else {
+            return Ok(None);
+        };
+
+        let mut redirects = vec![];
+        while matches!(
+            self.current_token,
+            Token::RedirectOperator(_) | Token::IoNumber(_)
+        ) {
+            redirects.push(self.parse_redirection()?);
+        }
+
+        match redirects.is_empty() {
+            true => Ok(Some(command)),
+            false => Ok(Some(Command::Redirected {
+                command: Box::new(command),
+                redirects,
+            })),
+        }
+    }
+
+    // Each `(` is matched by the recursion itself: a group left open runs
+    // out of input and is reported as incomplete, while a `)` without one
+    // stops the enclosing list and is rejected as an unexpected token.
+    fn parse_group(&mut self) -> Result<Command, ParseError> {
         self.advance();
-        let inner = self.parse()?;
+        self.skip_newlines();
+        let inner = self.parse_with_min_precedence(0)?;
         self.expect(Token::RParen)?;
 
         Ok(Command::Group {
@@ -81,46 +271,296 @@ impl Parser {
         })
     }
 
-    fn parse_command(&mut self) -> Result<Command, String> {
+    fn parse_brace_group(&mut self) -> Result<Command, ParseError> {
+        self.advance();
+        self.skip_newlines();
+        let inner = self.parse_with_min_precedence(0)?;
+        if !self.is_reserved("}") {
+            return Err(self.unexpected());
+        }
+        self.advance();
+
+        Ok(Command::BraceGroup {
+            group: Box::new(inner),
+        })
+    }
+
+    // `case word in [(]pattern[ | pattern]...) list terminator ... esac`,
+    // where the terminator may be left out before `esac`.
+    fn parse_case(&mut self) -> Result<Command, ParseError> {
+        self.advance();
+        let word = match &self.current_token {
+            Token::Word(word) => word.clone(),
+            _ => return Err(self.unexpected()),
+        };
+        self.advance();
+        self.skip_newlines();
+        if !self.is_reserved("in") {
+            return Err(self.unexpected());
+        }
+        self.advance();
+        self.skip_newlines();
+
+        let mut clauses = vec![];
+        while !self.is_reserved("esac") {
+            if self.current_token == Token::LParen {
+                self.advance();
+            }
+
+            let mut patterns = vec![];
+            loop {
+                match &self.current_token {
+                    Token::Word(pattern) => patterns.push(pattern.clone()),
+                    _ => return Err(self.unexpected()),
+                }
+                self.advance();
+                if self.current_token != Token::Pipe {
+                    break;
+                }
+                self.advance();
+            }
+            self.expect(Token::RParen)?;
+            self.skip_newlines();
+
+            let body = match self.at_list_end() {
+                true => None,
+                false => Some(self.parse_with_min_precedence(0)?),
+            };
+
+            let terminator = match self.current_token {
+                Token::CaseBreak => CaseTerminator::Break,
+                Token::CaseFallthrough => CaseTerminator::Fallthrough,
+                Token::CaseContinue => CaseTerminator::Continue,
+                _ if self.is_reserved("esac") => CaseTerminator::Break,
+                _ => return Err(self.unexpected()),
+            };
+            if !self.is_reserved("esac") {
+                self.advance();
+            }
+            self.skip_newlines();
+
+            clauses.push(CaseClause {
+                patterns,
+                body,
+                terminator,
+            });
+        }
+        self.advance();
+
+        Ok(Command::Case { word, clauses })
+    }
+
+    // `if list; then list; [elif list; then list;]... [else list;] fi`,
+    // where newlines may take the place of the `;`.
+    fn parse_if(&mut self) -> Result<Command, ParseError> {
+        let mut branches = vec![];
+        let mut otherwise = None;
+
+        loop {
+            self.advance();
+            self.skip_newlines();
+            let condition = self.parse_with_min_precedence(0)?;
+            if !self.is_reserved("then") {
+                return Err(self.unexpected());
+            }
+            self.advance();
+            self.skip_newlines();
+            branches.push((condition, self.parse_with_min_precedence(0)?));
+
+            if self.is_reserved("else") {
+                self.advance();
+                self.skip_newlines();
+                otherwise = Some(Box::new(self.parse_with_min_precedence(0)?));
+            }
+            if !self.is_reserved("elif") || otherwise.is_some() {
+                break;
+            }
+        }
+
+        if !self.is_reserved("fi") {
+            return Err(self.unexpected());
+        }
+        self.advance();
+
+        Ok(Command::If {
+            branches,
+            otherwise,
+        })
+    }
+
+    // `while list; do list; done`, or `until` which loops while the
+    // condition fails.
+    fn parse_while(&mut self) -> Result<Command, ParseError> {
+        let until = self.is_reserved("until");
+        self.advance();
+        self.skip_newlines();
+        let condition = self.parse_with_min_precedence(0)?;
+        let body = self.parse_do_group()?;
+
+        Ok(Command::While {
+            condition: Box::new(condition),
+            body: Box::new(body),
+            until,
+        })
+    }
+
+    fn parse_for(&mut self) -> Result<Command, ParseError> {
+        let (name, words) = self.parse_loop_header()?;
+        let body = self.parse_do_group()?;
+        Ok(Command::For {
+            name,
+            words,
+            body: Box::new(body),
+        })
+    }
+
+    fn parse_select(&mut self) -> Result<Command, ParseError> {
+        let (name, words) = self.parse_loop_header()?;
+        let body = self.parse_do_group()?;
+        Ok(Command::Select {
+            name,
+            words,
+            body: Box::new(body),
+        })
+    }
+
+    // The `name [in word...];` following `for` and `select`, where a newline
+    // may take the place of the `;`.
+    fn parse_loop_header(&mut self) -> Result<(String, Option<Vec<String>>), ParseError> {
+        self.advance();
+        let name = match &self.current_token {
+            Token::Word(name) if is_name(name) => name.clone(),
+            _ => return Err(self.unexpected()),
+        };
+        self.advance();
+        self.skip_newlines();
+
+        let mut words = None;
+        if self.is_reserved("in") {
+            self.advance();
+            let mut list = vec![];
+            while let Token::Word(word) = &self.current_token {
+                list.push(word.clone());
+                self.advance();
+            }
+            if !matches!(self.current_token, Token::Semicolon | Token::Newline) {
+                return Err(self.unexpected());
+            }
+            self.advance();
+            words = Some(list);
+        } else if self.current_token == Token::Semicolon {
+            self.advance();
+        }
+        self.skip_newlines();
+
+        Ok((name, words))
+    }
+
+    // The `do list done` body of a loop.
+    fn parse_do_group(&mut self) -> Result<Command, ParseError> {
+        if !self.is_reserved("do") {
+            return Err(self.unexpected());
+        }
+        self.advance();
+        self.skip_newlines();
+
+        let body = self.parse_with_min_precedence(0)?;
+        if !self.is_reserved("done") {
+            return Err(self.unexpected());
+        }
+        self.advance();
+        Ok(body)
+    }
+
+    // Reserved words are only recognized as unquoted words in command position.
+    fn is_reserved(&self, word: &str) -> bool {
+        matches!(&self.current_token, Token::Word(w) if w == word)
+    }
+
+    fn at_list_end(&self) -> bool {
+        matches!(
+            self.current_token,
+            Token::EOF
+                | Token::RParen
+                | Token::CaseBreak
+                | Token::CaseFallthrough
+                | Token::CaseContinue
+        ) || CLOSING.iter().any(|word| self.is_reserved(word))
+    }
+
+    fn parse_command(&mut self) -> Result<Command, ParseError> {
+        self.expand_alias();
+
         let mut words = vec![];
         let mut redirects = vec![];
 
         loop {
             match &self.current_token {
+                Token::Word(w) if words.is_empty() && CLOSING.contains(&w.as_str()) => break,
                 Token::Word(w) => {
                     words.push(w.clone());
                     self.advance();
                 }
-                Token::SingleQuoted(s) | Token::DoubleQuoted(s) => {
-                    words.push(s.clone());
-                    self.advance();
-                }
-                Token::RedirectOperator(_) => {
+                Token::RedirectOperator(_) | Token::IoNumber(_) => {
                     redirects.push(self.parse_redirection()?);
                 }
                 _ => break,
             }
         }
 
-        if words.is_empty() {
-            return Err("Empty command".to_string());
+        // A command may consist only of redirections, like `> file`.
+        if words.is_empty() && redirects.is_empty() {
+            return Err(self.unexpected());
+        }
+
+        // `name()` is followed by the compound command forming the body.
+        if words.len() == 1 && redirects.is_empty() && self.current_token == Token::LParen {
+            self.advance();
+            self.expect(Token::RParen)?;
+            self.skip_newlines();
+
+            let Some(body) = self.parse_compound()? else {
+                return Err(self.unexpected());
+            };
+
+            return Ok(Command::Function {
+                name: words.remove(0),
+                body: Box::new(body),
+            });
         }
 
+        let count = words.iter().take_while(|w| is_assignment(w)).count();
+        let assignments = words.drain(..count).collect();
+        let executable = if words.is_empty() {
+            String::new()
+        } else {
+            words.remove(0)
+        };
+
         Ok(Command::Simple {
-            executable: words.remove(0),
+            assignments,
+            executable,
             args: words,
             redirects,
         })
     }
 
-    fn parse_redirection(&mut self) -> Result<Redirection, String> {
+    fn parse_redirection(&mut self) -> Result<Redirection, ParseError> {
+        let io_number = match self.current_token {
+            Token::IoNumber(fd) => {
+                self.advance();
+                Some(fd)
+            }
+            _ => None,
+        };
+
         let rt = match &self.current_token {
             Token::RedirectOperator(t) => t.clone(),
-            _ => return Err("Expected redirect operator".to_string()),
+            _ => return Err(self.unexpected()),
         };
         self.advance();
 
-        let (mut fd, operator) = match rt {
+        let (fd, operator) = match rt {
             RedirectOperator::Overwrite => (Some(1), RedirectOperator::Overwrite),
             RedirectOperator::Append => (Some(1), RedirectOperator::Append),
             RedirectOperator::DuplicateOut => (Some(1), RedirectOperator::DuplicateOut),
@@ -129,25 +569,33 @@ impl Parser {
             RedirectOperator::HereDoc => (Some(0), RedirectOperator::HereDoc),
         };
 
-        if let Token::Word(n) = &self.current_token {
-            if let Ok(num) = n.parse::<u32>() {
-                fd = Some(num);
-                self.advance();
-            }
-        }
+        let fd = io_number.or(fd);
+        let duplicate = matches!(
+            operator,
+            RedirectOperator::DuplicateIn | RedirectOperator::DuplicateOut
+        );
 
         let target = match &self.current_token {
+            Token::Word(n) if duplicate && n == "-" => {
+                self.advance();
+                RedirectTarget::Close
+            }
+            Token::Word(n) if duplicate && n.parse::<u32>().is_ok() => {
+                let t = n.parse::<u32>().unwrap();
+                self.advance();
+                RedirectTarget::FileDescriptor(t)
+            }
             Token::Word(filename) => {
                 let t = filename.clone();
                 self.advance();
                 RedirectTarget::File(t)
             }
-            Token::SingleQuoted(s) | Token::DoubleQuoted(s) => {
-                let t = s.clone();
+            Token::HereDoc(heredoc) => {
+                let t = heredoc.clone();
                 self.advance();
-                RedirectTarget::File(t)
+                RedirectTarget::HereDoc(t)
             }
-            _ => return Err("Invalid redirect target".to_string()),
+            token => return Err(ParseError::UnexpectedToken(token.clone())),
         };
 
         Ok(Redirection {
@@ -157,3 +605,281 @@ impl Parser {
         })
     }
 }
+
+// Sends the stderr of the last command of a pipeline to its stdout.
+fn stderr_to_stdout(command: Command) -> Command {
+    let redirection = Redirection {
+        fd: Some(2),
+        operator: RedirectOperator::DuplicateOut,
+        target: RedirectTarget::FileDescriptor(1),
+    };
+
+    match command {
+        Command::Binary {
+            left,
+            right,
+            operator: Operator::Pipe,
+        } => Command::Binary {
+            left,
+            right: Box::new(stderr_to_stdout(*right)),
+            operator: Operator::Pipe,
+        },
+        Command::Simple {
+            assignments,
+            executable,
+            args,
+            mut redirects,
+        } => {
+            redirects.push(redirection);
+            Command::Simple {
+                assignments,
+                executable,
+                args,
+                redirects,
+            }
+        }
+        Command::Redirected {
+            command,
+            mut redirects,
+        } => {
+            redirects.push(redirection);
+            Command::Redirected { command, redirects }
+        }
+        command => Command::Redirected {
+            command: Box::new(command),
+            redirects: vec![redirection],
+        },
+    }
+}
+
+// Runs the last and-or list of a command list in the background. The lists
+// before it are left to run in the foreground.
+fn background(command: Command) -> Command {
+    match command {
+        Command::Binary {
+            left,
+            right,
+            operator: Operator::Semicolon,
+        } => Command::Binary {
+            left,
+            right: Box::new(background(*right)),
+            operator: Operator::Semicolon,
+        },
+        command => Command::Background {
+            command: Box::new(command),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell::tests::run;
+
+    fn parse(input: &str) -> Result<Command, ParseError> {
+        Parser::new(Lexer::new(input.to_string())).parse()
+    }
+
+    #[test]
+    fn leading_separator() {
+        for input in [";", "; echo a", "& echo a", " ;echo a"] {
+            let separator = match input.trim_start().starts_with(';') {
+                true => Token::Semicolon,
+                false => Token::Background,
+            };
+            assert_eq!(
+                parse(input).err(),
+                Some(ParseError::UnexpectedToken(separator)),
+                "{:?}",
+                input
+            );
+        }
+        assert!(parse("\n\necho a").is_ok());
+    }
+
+    #[test]
+    fn doubled_separator() {
+        for (input, token) in [
+            ("echo a ; ; echo b", Token::Semicolon),
+            ("echo a;\n;echo b", Token::Semicolon),
+            ("echo a & ; echo b", Token::Semicolon),
+            ("echo a && ; echo b", Token::Semicolon),
+            ("echo a;;echo b", Token::CaseBreak),
+        ] {
+            assert_eq!(
+                parse(input).err(),
+                Some(ParseError::UnexpectedToken(token)),
+                "{:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn trailing_separator() {
+        for input in [
+            "echo a;",
+            "echo a ; ",
+            "echo a &",
+            "echo a;\n",
+            "echo a; echo b;",
+        ] {
+            assert!(parse(input).is_ok(), "{:?}", input);
+        }
+        assert_eq!(parse("echo a &&").err(), Some(ParseError::Incomplete));
+    }
+
+    // The tree of a parsed command line, with each binary command in
+    // parentheses, e.g. `((a | b) && c)`.
+    fn shape(command: &Command) -> String {
+        match command {
+            Command::Simple { executable, .. } => executable.clone(),
+            Command::Binary {
+                left,
+                right,
+                operator,
+            } => {
+                let operator = match operator {
+                    Operator::Semicolon => ";",
+                    Operator::And => "&&",
+                    Operator::Or => "||",
+                    Operator::Pipe => "|",
+                };
+                format!("({} {} {})", shape(left), operator, shape(right))
+            }
+            Command::Background { command } => format!("{} &", shape(command)),
+            other => panic!("unexpected command {:?}", other),
+        }
+    }
+
+    #[test]
+    fn compound_commands_on_one_or_more_lines() {
+        for (one_line, lines) in [
+            ("if true; then echo hi; fi", "if true\nthen\n  echo hi\nfi"),
+            (
+                "if a; then b; elif c; then d; else e; fi",
+                "if a\nthen b\nelif c\nthen d\nelse e\nfi",
+            ),
+            (
+                "while false; do echo hi; done",
+                "while false\ndo\n  echo hi\ndone",
+            ),
+            (
+                "for x in a b; do echo $x; done",
+                "for x in a b\ndo\n  echo $x\ndone",
+            ),
+            ("for x; do echo $x; done", "for x\ndo echo $x\ndone"),
+        ] {
+            let expected = format!("{:?}", parse(one_line).unwrap());
+            assert_eq!(
+                format!("{:?}", parse(lines).unwrap()),
+                expected,
+                "{:?}",
+                lines
+            );
+        }
+
+        // The keywords are plain arguments without a separator before them.
+        for input in [
+            "if true echo hi fi",
+            "while true do echo hi done",
+            "for x in a do echo done",
+        ] {
+            assert_eq!(
+                parse(input).err(),
+                Some(ParseError::Incomplete),
+                "{:?}",
+                input
+            );
+        }
+        assert_eq!(
+            parse("if true; then; fi").err(),
+            Some(ParseError::UnexpectedToken(Token::Semicolon))
+        );
+    }
+
+    #[test]
+    fn pipe_with_stderr() {
+        assert_eq!(shape(&parse("a |& b | c").unwrap()), "((a | b) | c)");
+
+        // The added `2>&1` comes after the command's own redirections.
+        let Ok(Command::Binary { left, .. }) = parse("a 2> log > out |& b") else {
+            panic!("not a pipeline");
+        };
+        let Command::Simple { redirects, .. } = *left else {
+            panic!("not a simple command");
+        };
+        assert_eq!(redirects.len(), 3);
+        assert!(matches!(
+            redirects[2],
+            Redirection {
+                fd: Some(2),
+                operator: RedirectOperator::DuplicateOut,
+                target: RedirectTarget::FileDescriptor(1),
+            }
+        ));
+
+        let script = "ls /nonexistent |& grep -c No; { echo out; echo err >&2; } |& sort";
+        assert_eq!(run(script), (0, "1\nerr\nout\n".to_string()));
+    }
+
+    #[test]
+    fn operator_precedence() {
+        for (input, expected) in [
+            ("a | b && c | d", "((a | b) && (c | d))"),
+            ("a | b | c", "((a | b) | c)"),
+            ("a && b || c", "((a && b) || c)"),
+            ("a || b && c", "((a || b) && c)"),
+            ("a; b && c", "(a ; (b && c))"),
+            ("a && b; c | d", "((a && b) ; (c | d))"),
+            ("a | b & c", "((a | b) & ; c)"),
+            ("a & b & c", "((a & ; b &) ; c)"),
+            ("a && b & c &", "((a && b) & ; c &)"),
+        ] {
+            assert_eq!(shape(&parse(input).unwrap()), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn unbalanced_parentheses() {
+        for input in ["( echo", "(echo hi", "((echo a)", "(echo a\n"] {
+            assert_eq!(
+                parse(input).err(),
+                Some(ParseError::Incomplete),
+                "{:?}",
+                input
+            );
+        }
+        for input in ["echo )", "echo hi)", "( echo ))", ")"] {
+            assert_eq!(
+                parse(input).err(),
+                Some(ParseError::UnexpectedToken(Token::RParen)),
+                "{:?}",
+                input
+            );
+        }
+        assert!(parse("(echo a)").is_ok());
+    }
+
+    #[test]
+    fn case_terminators() {
+        let input = "case $x in a) x;; b) y;& c) z;;& d) w;; e) v\nesac";
+        let Ok(Command::Case { clauses, .. }) = parse(input) else {
+            panic!("expected a case command");
+        };
+        let terminators: Vec<CaseTerminator> = clauses
+            .iter()
+            .map(|clause| clause.terminator.clone())
+            .collect();
+        assert_eq!(
+            terminators,
+            [
+                CaseTerminator::Break,
+                CaseTerminator::Fallthrough,
+                CaseTerminator::Continue,
+                CaseTerminator::Break,
+                CaseTerminator::Break,
+            ]
+        );
+    }
+}
@@ -1,3 +1,190 @@
-pub fn prompt() -> String {
-    String::from("> ")
+use std::ffi::CStr;
+use std::sync::OnceLock;
+
+use libc::{c_char, geteuid, gethostname, getpwuid};
+
+use crate::expand::expand_heredoc;
+use crate::shell::Shell;
+
+// Readline ignores what is between these when measuring the prompt, which
+// keeps color codes from throwing off the cursor position.
+const IGNORE_START: char = '\x01';
+const IGNORE_END: char = '\x02';
+
+// The user and host names never change, so they are looked up once.
+static IDENTITY: OnceLock<(String, String)> = OnceLock::new();
+
+// Without `$PS1` the prompt shows the status of the last command in red when
+// it failed. Otherwise its backslash escapes are decoded, then its
+// parameters and command substitutions are expanded, every time it is shown.
+pub fn prompt(shell: &mut Shell) -> String {
+    let Some(ps1) = shell.variables.get("PS1").map(String::from) else {
+        return status_prompt(shell.last_status);
+    };
+
+    // `$?` is kept from the last command typed, not from a substitution in
+    // the prompt.
+    let status = shell.last_status;
+    let decoded = decode(&ps1, shell);
+    let prompt = expand_heredoc(&decoded, shell);
+    shell.last_status = status;
+    prompt
+}
+
+fn status_prompt(last_status: i32) -> String {
+    if last_status == 0 {
+        return String::from("> ");
+    }
+
+    format!(
+        "{}\x1b[31m{}{}{}\x1b[0m{} > ",
+        IGNORE_START, IGNORE_END, last_status, IGNORE_START, IGNORE_END
+    )
+}
+
+// Replaces the escapes of `$PS1`. What they produce is escaped in turn so
+// that the expansion that follows leaves it as it is.
+fn decode(ps1: &str, shell: &Shell) -> String {
+    let (user, host) = IDENTITY.get_or_init(identity);
+    let mut result = String::new();
+    let mut chars = ps1.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        let text = match chars.next() {
+            Some('u') => user.clone(),
+            Some('h') => host.split('.').next().unwrap_or_default().to_string(),
+            Some('H') => host.clone(),
+            Some('w') => directory(shell, false),
+            Some('W') => directory(shell, true),
+            Some('s') => shell
+                .name
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            Some('v') => env!("CARGO_PKG_VERSION").to_string(),
+            Some('?') => shell.last_status.to_string(),
+            Some('j') => shell.jobs.ids().len().to_string(),
+            Some('$') => String::from(if unsafe { geteuid() } == 0 { "#" } else { "$" }),
+            Some('n') => String::from("\n"),
+            Some('e') => String::from("\x1b"),
+            Some('a') => String::from("\x07"),
+            Some('[') => {
+                result.push(IGNORE_START);
+                continue;
+            }
+            Some(']') => {
+                result.push(IGNORE_END);
+                continue;
+            }
+            Some('\\') => String::from("\\"),
+            Some(c) => format!("\\{}", c),
+            None => String::from("\\"),
+        };
+
+        for c in text.chars() {
+            if matches!(c, '$' | '`' | '\\') {
+                result.push('\\');
+            }
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+// The current directory with `$HOME` shortened to `~`, or only its last
+// component.
+fn directory(shell: &Shell, basename: bool) -> String {
+    let pwd = shell.variables.get("PWD").unwrap_or_default();
+    let home = shell.variables.get("HOME").filter(|home| !home.is_empty());
+
+    if home == Some(pwd) {
+        return String::from("~");
+    }
+    if basename {
+        return match pwd.rsplit('/').next() {
+            Some("") | None => pwd.to_string(),
+            Some(name) => name.to_string(),
+        };
+    }
+
+    match home.and_then(|home| pwd.strip_prefix(home)) {
+        Some(rest) if rest.starts_with('/') => format!("~{}", rest),
+        _ => pwd.to_string(),
+    }
+}
+
+fn identity() -> (String, String) {
+    let user = unsafe {
+        let passwd = getpwuid(geteuid());
+        if passwd.is_null() || (*passwd).pw_name.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr((*passwd).pw_name)
+                .to_string_lossy()
+                .into_owned()
+        }
+    };
+
+    let mut buffer = [0 as c_char; 256];
+    let host = unsafe {
+        if gethostname(buffer.as_mut_ptr(), buffer.len()) == 0 {
+            CStr::from_ptr(buffer.as_ptr())
+                .to_string_lossy()
+                .into_owned()
+        } else {
+            String::new()
+        }
+    };
+
+    (user, host)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::shell::tests::in_child;
+
+    // The prompts shown after each of `commands`, run one after the other in
+    // a child, one per line.
+    fn prompts(ps1: &str, commands: &[&str]) -> String {
+        let (_, output) = in_child(|| {
+            let mut shell = Shell::new();
+            let _ = shell.variables.set("HOME", "/home/rush");
+            let _ = shell.variables.set("PS1", ps1);
+            for command in commands {
+                shell.eval(command);
+                let line = format!("{}\n", prompt(&mut shell));
+                let _ = std::io::stdout().write_all(line.as_bytes());
+            }
+            0
+        });
+        output
+    }
+
+    #[test]
+    fn directory_follows_cd() {
+        let output = prompts("\\w \\W $ ", &["cd /", "cd /usr/bin", "cd -"]);
+        assert_eq!(output, "/ / $ \n/usr/bin bin $ \n/\n/ / $ \n");
+
+        let output = prompts("\\w|\\W", &["cd /usr/bin", "HOME=/usr", "cd /usr"]);
+        assert_eq!(output, "/usr/bin|bin\n~/bin|bin\n~|~\n");
+    }
+
+    #[test]
+    fn substitution_keeps_status() {
+        let output = prompts("$(true)\\? ", &["false", "true", "(exit 3)"]);
+        assert_eq!(output, "1 \n0 \n3 \n");
+        // The command after the prompt still sees the status before it.
+        let output = prompts("$(true)[$status] ", &["false", "status=$?"]);
+        assert_eq!(output, "[] \n[1] \n");
+    }
 }
@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+
+use libc::{c_int, pid_t, sighandler_t};
+
+use crate::command::{Command, FdOpener, SystemOpener};
+use crate::jobs::Jobs;
+use crate::lexer::{Lexer, Token};
+use crate::options::Options;
+use crate::parser::{ParseError, Parser};
+use crate::signals;
+use crate::variables::Variables;
+
+// State that lives for as long as the shell, threaded through execution and
+// expansion.
+pub struct Shell {
+    pub name: String,            // `$0`
+    pub positional: Vec<String>, // `$1`, `$2`, ...
+    pub variables: Variables,
+    pub functions: HashMap<String, Command>,
+    pub aliases: HashMap<String, String>,
+    pub last_status: i32, // `$?`
+    pub options: Options,
+    pub jobs: Jobs,
+    pub job_control: bool, // Only set in the interactive shell itself
+    pub hash: Hash,
+    pub traps: HashMap<String, String>, // Commands set with `trap`, by condition
+    pub in_trap: bool,                  // Keeps traps from triggering themselves
+    pub in_condition: bool,             // Set for the left side of `&&` and `||`
+    pub substitutions: Vec<(pid_t, c_int)>, // Process substitutions still open
+    pub homes: HashMap<String, String>, // Home directories found for `~user`
+    pub loops: usize,                   // How many loops are running
+    pub breaking: usize,                // Loops left to leave after `break`
+    pub continuing: usize,              // Loops to go up before `continue` resumes one
+    pub returnable: usize,              // Functions and sourced scripts running
+    pub returning: Option<i32>,         // Status given to `return`, until it is done
+    pub opener: Box<dyn FdOpener>,      // Opens the files of redirections
+    pub signal_defaults: HashMap<c_int, sighandler_t>, // What `trap -` restores
+    pub substitution_status: Option<i32>, // Of the last command substitution since reset
+    pub bad_substitution: bool,         // Whether a `${...}` was malformed since reset
+}
+
+// Locations of commands found through `$PATH`, only valid for the value of
+// `$PATH` they were looked up with.
+#[derive(Default)]
+pub struct Hash {
+    pub path: String,
+    pub commands: HashMap<String, String>,
+}
+
+impl Default for Shell {
+    fn default() -> Shell {
+        Shell::new()
+    }
+}
+
+impl Shell {
+    pub fn new() -> Shell {
+        let mut variables = Variables::from_env();
+        if variables.get("PWD").is_none() {
+            if let Ok(cwd) = std::env::current_dir() {
+                let _ = variables.set("PWD", &cwd.to_string_lossy());
+            }
+        }
+
+        Shell {
+            name: String::from("rush"),
+            positional: vec![],
+            variables,
+            functions: HashMap::new(),
+            aliases: HashMap::new(),
+            last_status: 0,
+            options: Options::default(),
+            jobs: Jobs::default(),
+            job_control: false,
+            hash: Hash::default(),
+            traps: HashMap::new(),
+            in_trap: false,
+            in_condition: false,
+            substitutions: vec![],
+            homes: HashMap::new(),
+            loops: 0,
+            breaking: 0,
+            continuing: 0,
+            returnable: 0,
+            returning: None,
+            opener: Box::new(SystemOpener),
+            signal_defaults: HashMap::new(),
+            substitution_status: None,
+            bad_substitution: false,
+        }
+    }
+
+    // Runs the command set with `trap` for a condition like `ERR`, leaving
+    // `$?` as it was before.
+    pub fn run_trap(&mut self, condition: &str) {
+        if self.in_trap {
+            return;
+        }
+
+        if let Some(command) = self.traps.get(condition).cloned() {
+            let status = self.last_status;
+            self.in_trap = true;
+            self.eval(&command);
+            self.in_trap = false;
+            self.last_status = status;
+        }
+    }
+
+    // Whether `break`, `continue` or `return` is skipping the rest of a loop
+    // body or function.
+    pub fn jumping(&self) -> bool {
+        self.breaking > 0 || self.continuing > 0 || self.returning.is_some()
+    }
+
+    // Subshells start without the `DEBUG` and `ERR` traps, like in bash
+    // without `set -T` and `set -E`, and only keep the signals trapped to be
+    // ignored.
+    pub fn enter_subshell(&mut self) {
+        self.traps.remove("DEBUG");
+        self.traps.remove("ERR");
+        signals::reset(self);
+        self.traps.retain(|_, action| action.is_empty());
+        self.signal_defaults.clear();
+    }
+
+    // Lexes, parses and executes `input`, returning its exit status.
+    pub fn eval(&mut self, input: &str) -> i32 {
+        if self.is_blank(input) {
+            return self.last_status;
+        }
+
+        let command = self.parse(input);
+        self.run(command)
+    }
+
+    // Executes a script line by line the way the interactive shell does,
+    // gathering the lines of a command that continues past the end of one,
+    // and returns the status of the last command run. A syntax error stops
+    // the script with status 2, as nothing after it can be trusted to parse
+    // the way it was meant to.
+    pub fn eval_script(&mut self, script: &str) -> i32 {
+        let mut status = 0;
+        let mut buffer = String::new();
+        let mut start = 0;
+
+        for (number, line) in script.lines().enumerate() {
+            if buffer.is_empty() {
+                start = number + 1;
+            } else {
+                buffer.push('\n');
+            }
+            buffer.push_str(line);
+
+            if self.is_blank(&buffer) {
+                buffer.clear();
+                continue;
+            }
+
+            let command = self.parse(&buffer);
+            if let Err(ParseError::Incomplete) = command {
+                continue;
+            }
+
+            self.variables.set_line(start);
+            let error = command.is_err();
+            status = self.run(command);
+            if error || self.returning.is_some() {
+                return status;
+            }
+            buffer.clear();
+        }
+
+        if !buffer.is_empty() {
+            self.variables.set_line(start);
+            status = self.run(Err(ParseError::Incomplete));
+        }
+
+        status
+    }
+
+    // Whether `input` holds nothing but blanks and comments.
+    pub fn is_blank(&self, input: &str) -> bool {
+        let mut lexer = Lexer::new(input.to_string());
+        let mut token = lexer.next_token();
+        while token == Token::Newline {
+            token = lexer.next_token();
+        }
+        token == Token::EOF && !lexer.is_incomplete()
+    }
+
+    // Parses `input` with the options that affect lexing, like `extglob`.
+    pub fn parse(&self, input: &str) -> Result<Command, ParseError> {
+        let mut lexer = Lexer::new(input.to_string());
+        lexer.set_extglob(self.options.enabled("extglob"));
+        let mut parser = Parser::new(lexer);
+        parser.set_aliases(self.aliases.clone());
+        parser.parse()
+    }
+
+    // Executes the result of a parse, reporting syntax errors with status 2.
+    pub fn run(&mut self, command: Result<Command, ParseError>) -> i32 {
+        match command {
+            Ok(command) => command.execute(self),
+            Err(e) => {
+                eprintln!("rush: {}", e);
+                self.last_status = 2;
+                2
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{Read, Write};
+    use std::os::fd::FromRawFd;
+
+    use libc::{_exit, close, dup2, fork, open, pipe, waitpid, O_RDWR};
+    use libc::{WEXITSTATUS, WIFEXITED, WTERMSIG};
+
+    use super::*;
+
+    // Runs `body` in a forked child, so that the redirections and directory
+    // changes it makes can't race with other tests over the state of the
+    // test process, and returns its status and what it wrote to its standard
+    // output. Its standard input and error are `/dev/null`.
+    pub(crate) fn in_child(body: impl FnOnce() -> i32) -> (i32, String) {
+        let mut fds = [0; 2];
+        let _ = std::io::stdout().flush();
+
+        unsafe {
+            assert_eq!(pipe(fds.as_mut_ptr()), 0);
+            let pid = fork();
+            assert!(pid >= 0);
+
+            if pid == 0 {
+                let null = open(c"/dev/null".as_ptr(), O_RDWR);
+                dup2(null, 0);
+                dup2(fds[1], 1);
+                dup2(null, 2);
+                close(fds[0]);
+                close(fds[1]);
+                close(null);
+
+                let status = body();
+                let _ = std::io::stdout().flush();
+                _exit(status);
+            }
+
+            close(fds[1]);
+            let mut output = String::new();
+            let mut reader = std::fs::File::from_raw_fd(fds[0]);
+            let _ = reader.read_to_string(&mut output);
+
+            let mut status = 0;
+            waitpid(pid, &mut status, 0);
+            match WIFEXITED(status) {
+                true => (WEXITSTATUS(status), output),
+                false => (128 + WTERMSIG(status), output),
+            }
+        }
+    }
+
+    // Runs `script` the way `rush -c` does, in a child as `in_child` does.
+    pub(crate) fn run(script: &str) -> (i32, String) {
+        in_child(|| Shell::new().eval_script(script))
+    }
+
+    // A directory of its own for each test, holding empty files named
+    // `files`.
+    pub(crate) fn scratch(name: &str, files: &[&str]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("rush-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in files {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn run_in_child() {
+        assert_eq!(run("echo a; echo b >&2; exit 3"), (3, "a\n".to_string()));
+        assert_eq!(run("cd /; pwd"), (0, "/\n".to_string()));
+        assert_ne!(std::env::current_dir().unwrap(), std::path::Path::new("/"));
+    }
+}
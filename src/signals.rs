@@ -0,0 +1,166 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc::{c_int, sigaction, sigemptyset, sighandler_t, SA_RESTART, SIG_DFL, SIG_IGN};
+use libc::{
+    SIGALRM, SIGCHLD, SIGHUP, SIGINT, SIGPIPE, SIGQUIT, SIGTERM, SIGTSTP, SIGTTIN, SIGTTOU,
+    SIGUSR1, SIGUSR2, SIGWINCH,
+};
+
+use crate::shell::Shell;
+
+// The signals `trap` accepts, by their name without the `SIG` prefix, which
+// is also the condition their traps are kept under.
+const SIGNALS: [(&str, c_int); 13] = [
+    ("HUP", SIGHUP),
+    ("INT", SIGINT),
+    ("QUIT", SIGQUIT),
+    ("USR1", SIGUSR1),
+    ("USR2", SIGUSR2),
+    ("PIPE", SIGPIPE),
+    ("ALRM", SIGALRM),
+    ("TERM", SIGTERM),
+    ("CHLD", SIGCHLD),
+    ("TSTP", SIGTSTP),
+    ("TTIN", SIGTTIN),
+    ("TTOU", SIGTTOU),
+    ("WINCH", SIGWINCH),
+];
+
+// Set by `trap_handler` for the trapped signals received since their
+// commands last ran.
+static PENDING: [AtomicBool; 32] = [const { AtomicBool::new(false) }; 32];
+
+extern "C" fn trap_handler(signum: c_int) {
+    if let Some(pending) = PENDING.get(signum as usize) {
+        pending.store(true, Ordering::SeqCst);
+    }
+}
+
+// The name of a signal given by name, with or without `SIG`, or by number.
+pub fn signal_name(spec: &str) -> Option<&'static str> {
+    let name = spec.strip_prefix("SIG").unwrap_or(spec);
+    SIGNALS
+        .iter()
+        .find(|(known, number)| *known == name || spec.parse() == Ok(*number))
+        .map(|(known, _)| *known)
+}
+
+// The names of the signals in numeric order.
+pub fn signal_names() -> impl Iterator<Item = &'static str> {
+    SIGNALS.iter().map(|(name, _)| *name)
+}
+
+fn install(signal: c_int, handler: sighandler_t, flags: c_int) {
+    unsafe {
+        let mut action: sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler;
+        action.sa_flags = flags;
+        sigemptyset(&mut action.sa_mask);
+        sigaction(signal, &action, std::ptr::null_mut());
+    }
+}
+
+// Makes `handler` the disposition of `signal` in the shell itself until a
+// trap replaces it, and again after `trap -`. It is installed without
+// SA_RESTART so that readline sees its read interrupted.
+pub fn set_default(shell: &mut Shell, signal: c_int, handler: sighandler_t) {
+    shell.signal_defaults.insert(signal, handler);
+    install(signal, handler, 0);
+}
+
+// Sets the trap of the signal named `name`: `-` gives the signal back the
+// shell's own disposition, an empty action ignores it, and any other runs
+// as a command once the command being executed when it arrives finishes.
+pub fn set_trap(name: &str, action: &str, shell: &mut Shell) {
+    let Some(&(_, signal)) = SIGNALS.iter().find(|(known, _)| *known == name) else {
+        return;
+    };
+
+    match action {
+        "-" => {
+            shell.traps.remove(name);
+            let handler = shell.signal_defaults.get(&signal).copied();
+            install(signal, handler.unwrap_or(SIG_DFL), 0);
+        }
+        "" => {
+            shell.traps.insert(name.to_string(), String::new());
+            install(signal, SIG_IGN, 0);
+        }
+        command => {
+            shell.traps.insert(name.to_string(), command.to_string());
+            install(
+                signal,
+                trap_handler as *const () as sighandler_t,
+                SA_RESTART,
+            );
+        }
+    }
+}
+
+// Runs the traps of the signals received since the last call, unless a trap
+// is already running, in which case they wait for the next one.
+pub fn run_pending(shell: &mut Shell) {
+    if shell.in_trap {
+        return;
+    }
+
+    for (name, signal) in SIGNALS {
+        if PENDING[signal as usize].swap(false, Ordering::SeqCst) {
+            shell.run_trap(name);
+        }
+    }
+}
+
+// Gives a new process the dispositions commands start with: the signals the
+// shell handles or traps go back to their defaults, except the ones trapped
+// with an empty action, which stay ignored. Other signals keep what the
+// shell inherited, so that `nohup` still works through it.
+pub fn reset(shell: &Shell) {
+    for (name, signal) in SIGNALS {
+        let handler = match shell.traps.get(name) {
+            Some(action) if action.is_empty() => SIG_IGN,
+            Some(_) => SIG_DFL,
+            None if shell.signal_defaults.contains_key(&signal) => SIG_DFL,
+            None => continue,
+        };
+        install(signal, handler, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::shell::tests::in_child;
+
+    // The disposition `signal` currently has in this process.
+    fn disposition(signal: c_int) -> sighandler_t {
+        unsafe {
+            let mut action: sigaction = std::mem::zeroed();
+            sigaction(signal, std::ptr::null(), &mut action);
+            action.sa_sigaction
+        }
+    }
+
+    #[test]
+    fn ignored_signal_survives_reset() {
+        // Dispositions are process-wide, so the shell runs in a child.
+        let (_, output) = in_child(|| {
+            let mut shell = Shell::new();
+            shell.eval_script("trap '' QUIT");
+            let ignored = disposition(SIGQUIT) == SIG_IGN;
+            let mut output = format!("{:?} {ignored}\n", shell.traps.get("QUIT"));
+            reset(&shell);
+            output += &format!("{}\n", disposition(SIGQUIT) == SIG_IGN);
+
+            shell.eval_script("trap - QUIT");
+            let default = disposition(SIGQUIT) == SIG_DFL;
+            output += &format!("{:?} {default}\n", shell.traps.get("QUIT"));
+            // `println!` output is captured by the test harness.
+            let _ = std::io::stdout().write_all(output.as_bytes());
+            0
+        });
+        assert_eq!(output, "Some(\"\") true\ntrue\nNone true\n");
+    }
+}
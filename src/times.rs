@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use libc::{clock_gettime, getrusage, rusage, timespec, timeval};
+use libc::{CLOCK_MONOTONIC, RUSAGE_CHILDREN, RUSAGE_SELF};
+
+// What `time` prints when `$TIMEFORMAT` is unset.
+pub const DEFAULT_FORMAT: &str = "\nreal\t%3lR\nuser\t%3lU\nsys\t%3lS";
+
+// Elapsed real time along with the CPU time used by the shell and the
+// children it has waited for.
+#[derive(Debug, Clone, Copy)]
+pub struct Times {
+    pub real: Duration,
+    pub user: Duration,
+    pub sys: Duration,
+}
+
+impl Times {
+    pub fn now() -> Times {
+        unsafe {
+            let mut clock: timespec = std::mem::zeroed();
+            clock_gettime(CLOCK_MONOTONIC, &mut clock);
+
+            let mut own: rusage = std::mem::zeroed();
+            let mut children: rusage = std::mem::zeroed();
+            getrusage(RUSAGE_SELF, &mut own);
+            getrusage(RUSAGE_CHILDREN, &mut children);
+
+            Times {
+                real: Duration::new(clock.tv_sec as u64, clock.tv_nsec as u32),
+                user: duration(own.ru_utime) + duration(children.ru_utime),
+                sys: duration(own.ru_stime) + duration(children.ru_stime),
+            }
+        }
+    }
+
+    pub fn since(&self, start: &Times) -> Times {
+        Times {
+            real: self.real.saturating_sub(start.real),
+            user: self.user.saturating_sub(start.user),
+            sys: self.sys.saturating_sub(start.sys),
+        }
+    }
+
+    // Formats the times like bash does with `$TIMEFORMAT`: `%R`, `%U` and
+    // `%S` stand for the real, user and system times, optionally preceded
+    // by the number of decimals and by `l` for the `1m2.345s` form, and
+    // `%P` for the CPU percentage.
+    pub fn format(&self, format: &str) -> String {
+        let chars: Vec<char> = format.chars().collect();
+        let mut output = String::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            if chars[i] != '%' {
+                output.push(chars[i]);
+                i += 1;
+                continue;
+            }
+            i += 1;
+
+            let mut precision = 3;
+            if let Some(digit) = chars.get(i).and_then(|c| c.to_digit(10)) {
+                precision = digit.min(3) as usize;
+                i += 1;
+            }
+
+            let long = chars.get(i) == Some(&'l');
+            if long {
+                i += 1;
+            }
+
+            let time = match chars.get(i) {
+                Some('R') => self.real,
+                Some('U') => self.user,
+                Some('S') => self.sys,
+                Some('P') => {
+                    let cpu = (self.user + self.sys).as_secs_f64();
+                    let real = self.real.as_secs_f64();
+                    let percent = if real > 0.0 { cpu * 100.0 / real } else { 0.0 };
+                    output.push_str(&format!("{:.2}", percent));
+                    i += 1;
+                    continue;
+                }
+                Some('%') => {
+                    output.push('%');
+                    i += 1;
+                    continue;
+                }
+                _ => {
+                    output.push('%');
+                    continue;
+                }
+            };
+            i += 1;
+
+            let seconds = time.as_secs_f64();
+            if long {
+                let minutes = (seconds / 60.0).floor();
+                let rest = seconds - minutes * 60.0;
+                output.push_str(&format!("{}m{:.*}s", minutes, precision, rest));
+            } else {
+                output.push_str(&format!("{:.*}", precision, seconds));
+            }
+        }
+
+        output
+    }
+}
+
+fn duration(time: timeval) -> Duration {
+    Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+}
@@ -0,0 +1,477 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::arithmetic;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(String),
+    Array(BTreeMap<usize, String>), // Indexed arrays may have gaps
+    Unset,                          // Given attributes or a scope, but no value yet
+}
+
+impl Default for Value {
+    fn default() -> Value {
+        Value::Scalar(String::new())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Variable {
+    pub value: Value,
+    pub exported: bool, // Passed in the environment of commands
+    pub integer: bool,  // Assignments are evaluated as arithmetic
+    pub readonly: bool, // Assignments fail
+}
+
+impl Variable {
+    pub fn unset() -> Variable {
+        Variable {
+            value: Value::Unset,
+            ..Variable::default()
+        }
+    }
+
+    // The value of a scalar, or the first element of an array, which is what
+    // referencing an array without a subscript yields.
+    pub fn scalar(&self) -> &str {
+        match &self.value {
+            Value::Scalar(value) => value,
+            Value::Array(elements) => elements.get(&0).map_or("", |value| value.as_str()),
+            Value::Unset => "",
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.value != Value::Unset
+    }
+
+    pub fn is_array(&self) -> bool {
+        matches!(self.value, Value::Array(_))
+    }
+
+    // Turns a scalar into an array holding its value as the first element.
+    pub fn make_array(&mut self) {
+        let mut elements = BTreeMap::new();
+        match &self.value {
+            Value::Scalar(value) if !value.is_empty() => {
+                elements.insert(0, value.clone());
+            }
+            Value::Array(_) => return,
+            _ => {}
+        }
+        self.value = Value::Array(elements);
+    }
+
+    // The elements of an array, or the value of a scalar on its own.
+    pub fn elements(&self) -> Vec<String> {
+        match &self.value {
+            Value::Scalar(value) => vec![value.clone()],
+            Value::Array(elements) => elements.values().cloned().collect(),
+            Value::Unset => vec![],
+        }
+    }
+
+    // The value as `declare -p` prints it, arrays as `([0]="a" [1]="b")`.
+    pub fn literal(&self) -> String {
+        match &self.value {
+            Value::Scalar(value) => format!("\"{}\"", value),
+            Value::Array(elements) => {
+                let elements: Vec<String> = elements
+                    .iter()
+                    .map(|(index, value)| format!("[{}]=\"{}\"", index, value))
+                    .collect();
+                format!("({})", elements.join(" "))
+            }
+            Value::Unset => String::new(),
+        }
+    }
+
+    // The attributes as `declare` prints them, `--` when there are none.
+    pub fn flags(&self) -> String {
+        let flags: String = [
+            (self.is_array(), 'a'),
+            (self.integer, 'i'),
+            (self.readonly, 'r'),
+            (self.exported, 'x'),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|&(_, flag)| flag)
+        .collect();
+
+        if flags.is_empty() {
+            String::from("--")
+        } else {
+            format!("-{}", flags)
+        }
+    }
+}
+
+// The shell's variables, seeded from the environment it was started with.
+// Each function call pushes a scope for its local variables, which shadow
+// those of its callers until it returns.
+#[derive(Debug, Clone)]
+pub struct Variables {
+    scopes: Vec<HashMap<String, Variable>>, // The global scope comes first
+    random: u32,                            // State of the `$RANDOM` generator
+    seconds: (Instant, u64),                // `$SECONDS` counts up from the value set then
+    line: usize,                            // `$LINENO`
+}
+
+impl Variables {
+    pub fn from_env() -> Variables {
+        let globals = std::env::vars()
+            .map(|(name, value)| {
+                let variable = Variable {
+                    value: Value::Scalar(value),
+                    exported: true,
+                    ..Variable::default()
+                };
+                (name, variable)
+            })
+            .collect();
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u32)
+            .unwrap_or(0);
+
+        Variables {
+            scopes: vec![globals],
+            random: time ^ std::process::id(),
+            seconds: (Instant::now(), 0),
+            line: 0,
+        }
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn set_line(&mut self, line: usize) {
+        self.line = line;
+    }
+
+    pub fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    pub fn pop_scope(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+
+    // Whether a function is running, so that `local` may be used.
+    pub fn in_function(&self) -> bool {
+        self.scopes.len() > 1
+    }
+
+    // Looks a variable up, computing the value of dynamic ones like
+    // `$RANDOM`, which changes on every reference.
+    pub fn value(&mut self, name: &str) -> Option<String> {
+        match name {
+            "RANDOM" => {
+                self.random = self.random.wrapping_mul(1103515245).wrapping_add(12345);
+                Some(((self.random >> 16) & 0x7fff).to_string())
+            }
+            "SECONDS" => {
+                let (start, base) = self.seconds;
+                Some((base + start.elapsed().as_secs()).to_string())
+            }
+            "LINENO" => Some(self.line.to_string()),
+            _ => self.get(name).map(|value| value.to_string()),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.variable(name)
+            .filter(|variable| variable.is_set())
+            .map(|variable| variable.scalar())
+    }
+
+    pub fn variable(&self, name: &str) -> Option<&Variable> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    // The innermost scope defining `name`, or the global one.
+    fn scope_of(&mut self, name: &str) -> &mut HashMap<String, Variable> {
+        let index = self
+            .scopes
+            .iter()
+            .rposition(|scope| scope.contains_key(name))
+            .unwrap_or(0);
+        &mut self.scopes[index]
+    }
+
+    // Sets the value of a variable, keeping its attributes. Variables that
+    // are not defined in any scope are created as globals.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "RANDOM" => {
+                self.random = value.parse().unwrap_or(0);
+                return Ok(());
+            }
+            "SECONDS" => {
+                self.seconds = (Instant::now(), value.parse().unwrap_or(0));
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let value = self.assignable(name, value)?;
+        let variable = self.scope_of(name).entry(name.to_string()).or_default();
+        match &mut variable.value {
+            Value::Array(elements) => {
+                elements.insert(0, value);
+            }
+            scalar => *scalar = Value::Scalar(value),
+        }
+        Ok(())
+    }
+
+    // Replaces the value of a variable with an array of `values`.
+    pub fn set_array(&mut self, name: &str, values: Vec<String>) -> Result<(), String> {
+        let mut elements = BTreeMap::new();
+        for (index, value) in values.into_iter().enumerate() {
+            elements.insert(index, self.assignable(name, &value)?);
+        }
+
+        self.scope_of(name)
+            .entry(name.to_string())
+            .or_default()
+            .value = Value::Array(elements);
+        Ok(())
+    }
+
+    // Appends to a variable for `NAME+=value`: integers are added to, other
+    // values are concatenated.
+    pub fn append(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let current = self.element(name, 0).unwrap_or_default();
+        let value = self.appended(name, current, value);
+        self.set(name, &value)
+    }
+
+    // Appends to one element of an array, `NAME[index]+=value`.
+    pub fn append_element(&mut self, name: &str, index: i64, value: &str) -> Result<(), String> {
+        let current = self.element(name, index).unwrap_or_default();
+        let value = self.appended(name, current, value);
+        self.set_element(name, index, &value)
+    }
+
+    // Adds `values` after the last element of an array, `NAME+=(a b c)`.
+    pub fn append_array(&mut self, name: &str, values: Vec<String>) -> Result<(), String> {
+        let values = values
+            .iter()
+            .map(|value| self.assignable(name, value))
+            .collect::<Result<Vec<String>, String>>()?;
+
+        let variable = self.scope_of(name).entry(name.to_string()).or_default();
+        variable.make_array();
+        if let Value::Array(elements) = &mut variable.value {
+            let next = elements.keys().next_back().map_or(0, |index| index + 1);
+            for (offset, value) in values.into_iter().enumerate() {
+                elements.insert(next + offset, value);
+            }
+        }
+        Ok(())
+    }
+
+    // The value to assign when appending, left for `assignable` to evaluate
+    // as a sum for integer variables.
+    fn appended(&self, name: &str, current: String, value: &str) -> String {
+        match self.variable(name) {
+            Some(variable) if variable.integer => {
+                let current = if current.is_empty() {
+                    "0".into()
+                } else {
+                    current
+                };
+                format!("{}+({})", current, value)
+            }
+            _ => current + value,
+        }
+    }
+
+    // Sets one element of an array, turning a scalar into an array first.
+    // Negative indices count back from the end of the array.
+    pub fn set_element(&mut self, name: &str, index: i64, value: &str) -> Result<(), String> {
+        let value = self.assignable(name, value)?;
+        let index = match self.index(name, index) {
+            Some(index) => index,
+            None => return Err(format!("{}[{}]: bad array subscript", name, index)),
+        };
+
+        let variable = self.scope_of(name).entry(name.to_string()).or_default();
+        variable.make_array();
+        if let Value::Array(elements) = &mut variable.value {
+            elements.insert(index, value);
+        }
+        Ok(())
+    }
+
+    // The element at `index` of an array, where a scalar counts as an
+    // array of one element.
+    pub fn element(&self, name: &str, index: i64) -> Option<String> {
+        let index = self.index(name, index)?;
+        match &self.variable(name)?.value {
+            Value::Scalar(value) if index == 0 => Some(value.clone()),
+            Value::Scalar(_) | Value::Unset => None,
+            Value::Array(elements) => elements.get(&index).cloned(),
+        }
+    }
+
+    // Resolves a negative index against the end of an array, or None when
+    // it goes back past the start.
+    pub fn index(&self, name: &str, index: i64) -> Option<usize> {
+        if index >= 0 {
+            return Some(index as usize);
+        }
+
+        let len = match self.variable(name).map(|variable| &variable.value) {
+            Some(Value::Array(elements)) => elements.keys().next_back().map_or(0, |last| last + 1),
+            Some(Value::Scalar(_)) => 1,
+            Some(Value::Unset) | None => 0,
+        };
+        usize::try_from(len as i64 + index).ok()
+    }
+
+    // Checks that `name` may be assigned, and evaluates `value` when the
+    // variable has the integer attribute.
+    fn assignable(&self, name: &str, value: &str) -> Result<String, String> {
+        let (readonly, integer) = match self.variable(name) {
+            Some(variable) => (variable.readonly, variable.integer),
+            None => (false, false),
+        };
+
+        if readonly {
+            return Err(format!("{}: readonly variable", name));
+        }
+
+        if integer {
+            Ok(arithmetic::evaluate(value, self)?.to_string())
+        } else {
+            Ok(value.to_string())
+        }
+    }
+
+    // The variable `name` for `declare` to change, created unset in the
+    // innermost scope when `local` is set and in the global scope otherwise.
+    pub fn declare(&mut self, name: &str, local: bool) -> &mut Variable {
+        let scope = if local {
+            self.scopes.last_mut().unwrap()
+        } else {
+            self.scope_of(name)
+        };
+
+        scope
+            .entry(name.to_string())
+            .or_insert_with(Variable::unset)
+    }
+
+    // Replaces a variable entirely, or removes it when `variable` is `None`.
+    pub fn restore(&mut self, name: &str, variable: Option<Variable>) {
+        let scope = self.scope_of(name);
+        match variable {
+            Some(variable) => scope.insert(name.to_string(), variable),
+            None => scope.remove(name),
+        };
+    }
+
+    // Removes a variable from the innermost scope defining it.
+    pub fn unset(&mut self, name: &str) -> Result<(), String> {
+        if self
+            .variable(name)
+            .is_some_and(|variable| variable.readonly)
+        {
+            return Err(format!("{}: cannot unset: readonly variable", name));
+        }
+        self.scope_of(name).remove(name);
+        Ok(())
+    }
+
+    // Marks a variable for export, keeping the attribute on an unset one so
+    // that a later assignment is exported too.
+    pub fn export(&mut self, name: &str) {
+        self.scope_of(name)
+            .entry(name.to_string())
+            .or_insert_with(Variable::unset)
+            .exported = true;
+    }
+
+    // Every visible variable, sorted by name.
+    pub fn list(&self) -> Vec<(String, Variable)> {
+        let mut variables: HashMap<&String, &Variable> = HashMap::new();
+        for scope in &self.scopes {
+            variables.extend(scope.iter());
+        }
+
+        let mut variables: Vec<(String, Variable)> = variables
+            .into_iter()
+            .map(|(name, variable)| (name.clone(), variable.clone()))
+            .collect();
+
+        variables.sort_by(|a, b| a.0.cmp(&b.0));
+        variables
+    }
+
+    // The exported variables, sorted by name.
+    pub fn environment(&self) -> Vec<(String, String)> {
+        self.list()
+            .into_iter()
+            .filter(|(_, variable)| variable.exported && variable.is_set() && !variable.is_array())
+            .map(|(name, variable)| (name, variable.scalar().to_string()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell::tests::run;
+
+    #[test]
+    fn declared_without_value() {
+        let mut variables = Variables::from_env();
+        variables.declare("rush_x", false).integer = true;
+        assert_eq!(variables.get("rush_x"), None);
+        assert_eq!(
+            variables.variable("rush_x").map(Variable::flags),
+            Some("-i".into())
+        );
+        variables.set("rush_x", "1+1").unwrap();
+        assert_eq!(variables.get("rush_x"), Some("2"));
+    }
+
+    #[test]
+    fn local_and_declare_leave_unset() {
+        let script = "f() { local x; echo ${x-d}; x=2; echo ${x-d}; }; x=1; f; echo $x";
+        assert_eq!(run(script), (0, "d\n2\n1\n".to_string()));
+        let script = "declare x; echo ${x-d}; declare -p x; declare -i x; x=1+1; echo $x";
+        assert_eq!(run(script), (0, "d\ndeclare -- x\n2\n".to_string()));
+    }
+
+    #[test]
+    fn export_leaves_unset() {
+        let script = "unset FOO; export FOO; echo ${FOO-unset}; env | grep -c ^FOO=";
+        assert_eq!(run(script), (1, "unset\n0\n".to_string()));
+    }
+
+    #[test]
+    fn array_indices() {
+        let script = "a=(x y z); echo \"[${a[5]}]\" ${a[-1]} ${a[-3]} \"[${a[-4]}]\"";
+        assert_eq!(run(script), (0, "[] z x []\n".to_string()));
+
+        let mut variables = Variables::from_env();
+        variables.set_element("rush_a", 1, "y").unwrap();
+        assert_eq!(variables.index("rush_a", -2), Some(0));
+        assert_eq!(variables.index("rush_a", -3), None);
+        variables.set_element("rush_a", -1, "z").unwrap();
+        assert_eq!(variables.element("rush_a", 1), Some("z".into()));
+        assert_eq!(
+            variables.set_element("rush_a", -3, "w"),
+            Err("rush_a[-3]: bad array subscript".into())
+        );
+    }
+}
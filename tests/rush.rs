@@ -0,0 +1,247 @@
+// Checks that need the shell as its own process: what it writes to its
+// standard error, how it starts and exits, and how it uses a terminal.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+// A directory of its own for each test, emptied first.
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rush-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Runs `rush -c script` in `dir`.
+fn rush(script: &str, dir: &PathBuf) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rush"))
+        .args(["-c", script])
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn builtin_stderr_redirected() {
+    let dir = scratch("builtin-stderr");
+    let output = rush("cd /nonexistent 2> err.log", &dir);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stderr.is_empty());
+
+    let log = std::fs::read_to_string(dir.join("err.log")).unwrap();
+    assert_eq!(log, "rush: cd: /nonexistent: No such file or directory\n");
+
+    // Standard error is back in place for the next command.
+    let output = rush("cd /nonexistent 2> err.log; cd /nonexistent", &dir);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "rush: cd: /nonexistent: No such file or directory\n"
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn negative_index_past_start() {
+    let dir = scratch("negative-index");
+    let output = rush("a=(x y z); echo \"[${a[-4]}]\"", &dir);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "[]\n");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "rush: a: bad array subscript\n"
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn builtin_usage_on_stderr() {
+    let dir = scratch("builtin-usage");
+    let output = rush("cd -x", &dir);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "rush: cd: -x: invalid option\nrush: cd: usage: cd [-L|-P] [dir]\n"
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn redirect_error_names_the_file() {
+    let dir = scratch("redirect-error");
+    let output = rush("echo hi > missing/f", &dir);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "rush: missing/f: No such file or directory\n"
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn select_menu_on_stderr() {
+    let dir = scratch("select-menu");
+    let output = rush(
+        "echo 2 | { PS3='> '; select v in a b; do echo $v; done; }",
+        &dir,
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "b\n");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "1) a\n2) b\n> > \n"
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn shlvl_counts_nested_shells() {
+    let dir = scratch("shlvl");
+    let script = format!(
+        "echo $SHLVL; {} -c 'echo $SHLVL'",
+        env!("CARGO_BIN_EXE_rush")
+    );
+    for (start, expected) in [
+        (Some("1"), "2\n3\n"),
+        (Some("abc"), "1\n2\n"),
+        (None, "1\n2\n"),
+    ] {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_rush"));
+        match start {
+            Some(start) => command.env("SHLVL", start),
+            None => command.env_remove("SHLVL"),
+        };
+        let output = command
+            .args(["-c", &script])
+            .current_dir(&dir)
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            expected,
+            "{:?}",
+            start
+        );
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn syntax_errors_exit_2() {
+    let dir = scratch("syntax-errors");
+    for (script, message) in [
+        ("if", "rush: syntax error: unexpected end of file\n"),
+        ("echo (", "rush: syntax error: unexpected end of file\n"),
+        (
+            "echo before; fi",
+            "rush: syntax error near unexpected token 'fi'\n",
+        ),
+    ] {
+        let output = rush(script, &dir);
+        assert_eq!(output.status.code(), Some(2), "{:?}", script);
+        assert!(output.stdout.is_empty(), "{:?}", script);
+        assert_eq!(String::from_utf8_lossy(&output.stderr), message);
+    }
+
+    // A script runs up to the line with the error.
+    std::fs::write(dir.join("bad.sh"), "echo a\nfi\necho b\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rush"))
+        .arg("bad.sh")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "a\n");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// Runs `read` with its input on a terminal, typing `input` once the prompt
+// shows, and returns what the terminal displayed and what the shell printed.
+fn read_on_terminal(options: &str, input: &str) -> (String, String) {
+    let (mut master, slave) = unsafe {
+        let (mut master, mut slave) = (0, 0);
+        let name = std::ptr::null_mut();
+        let result = libc::openpty(
+            &mut master,
+            &mut slave,
+            name,
+            std::ptr::null(),
+            std::ptr::null(),
+        );
+        assert_eq!(result, 0);
+        (File::from_raw_fd(master), OwnedFd::from_raw_fd(slave))
+    };
+
+    let script = format!("read {} -p 'Password: ' x; echo \"got $x\"", options);
+    let child = Command::new(env!("CARGO_BIN_EXE_rush"))
+        .args(["-c", &script])
+        .stdin(Stdio::from(slave.try_clone().unwrap()))
+        .stderr(Stdio::from(slave))
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // The terminal echoes what is typed as it arrives, so the input waits
+    // for `read` to have set the terminal up and shown its prompt.
+    let mut shown = String::new();
+    while !shown.ends_with("Password: ") {
+        let mut buffer = [0; 64];
+        let n = master.read(&mut buffer).unwrap();
+        shown += &String::from_utf8_lossy(&buffer[..n]);
+    }
+    master.write_all(input.as_bytes()).unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    unsafe { libc::fcntl(master.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+    let mut buffer = [0; 256];
+    if let Ok(n) = master.read(&mut buffer) {
+        shown += &String::from_utf8_lossy(&buffer[..n]);
+    }
+    (shown, String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[test]
+fn read_prompt_and_silent_input() {
+    let (shown, output) = read_on_terminal("", "hunter2\n");
+    assert_eq!(
+        (shown.as_str(), output.as_str()),
+        ("Password: hunter2\r\n", "got hunter2\n")
+    );
+    let (shown, output) = read_on_terminal("-s", "hunter2\n");
+    assert_eq!(
+        (shown.as_str(), output.as_str()),
+        ("Password: ", "got hunter2\n")
+    );
+
+    // Without a terminal there is no prompt.
+    let dir = scratch("read-prompt");
+    let output = rush("echo hi | { read -p 'Password: ' x; echo $x; }", &dir);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "hi\n");
+    assert!(output.stderr.is_empty());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn end_of_input_keeps_last_status() {
+    let dir = scratch("end-of-input");
+    for (input, status) in [("false\n", 1), ("true\n", 0), ("(exit 7)\n", 7)] {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rush"))
+            .arg("--norc")
+            .env("HOME", &dir)
+            .current_dir(&dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        assert_eq!(child.wait().unwrap().code(), Some(status), "{:?}", input);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}